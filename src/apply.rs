@@ -0,0 +1,217 @@
+//! Transactional application of steps.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::git::Git;
+use crate::plan::{self, Step};
+use crate::rename::ApplyError;
+use crate::vfs::{Filesystem, Os};
+
+/// Applies steps to a filesystem one after another, such that all of them can be rolled back if
+/// one fails.
+///
+/// Removed paths are only moved aside and removed once the transaction is committed.
+#[derive(Debug)]
+pub struct Transaction<F: Filesystem = Os> {
+    fs: F,
+    applied: Vec<(Step, Step)>,
+    removals: Vec<PathBuf>,
+    git: Option<Git>,
+}
+
+impl<F: Filesystem> Transaction<F> {
+    /// Creates a new transaction on `fs`, renames of tracked files are staged in the index of
+    /// `git`.
+    pub fn new(fs: F, git: Option<Git>) -> Self {
+        Self {
+            fs,
+            applied: vec![],
+            removals: vec![],
+            git,
+        }
+    }
+
+    /// Applies `steps` one after another in a new transaction on `fs`, if a step fails all applied
+    /// steps are rolled back.
+    ///
+    /// Only [`ApplyError::Failed`] is returned.
+    pub fn run(fs: F, git: Option<Git>, steps: &[Step]) -> Result<Self, ApplyError> {
+        let mut transaction = Self::new(fs, git);
+        for (idx, step) in steps.iter().enumerate() {
+            if let Err(err) = transaction.apply(step) {
+                return Err(ApplyError::Failed {
+                    step: step.clone(),
+                    err,
+                    rollback: transaction.rollback(),
+                    idx,
+                });
+            }
+        }
+
+        Ok(transaction)
+    }
+
+    /// Returns the number of applied steps.
+    pub fn len(&self) -> usize {
+        self.applied.len()
+    }
+
+    /// Returns whether no steps were applied.
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty()
+    }
+
+    /// Returns the applied steps and their inverses.
+    pub fn applied(&self) -> &[(Step, Step)] {
+        &self.applied
+    }
+
+    /// Applies a single step and records its inverse.
+    pub fn apply(&mut self, step: &Step) -> io::Result<()> {
+        let inverse = match step {
+            Step::Rename { from, to } => {
+                step.apply(&mut self.fs)?;
+                let inverse = Step::Rename {
+                    from: to.clone(),
+                    to: from.clone(),
+                };
+
+                self.stage(step, &inverse)?;
+                inverse
+            }
+            Step::Exchange { .. } => {
+                step.apply(&mut self.fs)?;
+                self.stage(step, step)?;
+                step.clone()
+            }
+            Step::Copy { to, .. } => {
+                step.apply(&mut self.fs)?;
+                Step::Remove { path: to.clone() }
+            }
+            Step::Remove { path } => self.remove_later(path)?,
+            Step::Trash { path } => {
+                let trashed = self.fs.trash(path)?;
+                Step::Restore {
+                    file: trashed.file,
+                    info: trashed.info,
+                    path: path.clone(),
+                }
+            }
+            Step::Restore { path, .. } => {
+                step.apply(&mut self.fs)?;
+                Step::Trash { path: path.clone() }
+            }
+            Step::CreateDir { path } => {
+                step.apply(&mut self.fs)?;
+                Step::RemoveDir { path: path.clone() }
+            }
+            Step::RemoveDir { path } => {
+                // entries which are only removed on commit don't keep a directory from being
+                // removed, so it is removed on commit too
+                let mut pending = false;
+                for name in self.fs.read_dir(path)? {
+                    let entry = path.join(name);
+                    if !self.removals.contains(&entry) {
+                        pending = false;
+                        break;
+                    }
+
+                    pending = true;
+                }
+
+                if pending {
+                    self.remove_later(path)?
+                } else {
+                    step.apply(&mut self.fs)?;
+                    Step::CreateDir { path: path.clone() }
+                }
+            }
+        };
+
+        self.applied.push((step.clone(), inverse));
+        Ok(())
+    }
+
+    /// Stages an applied rename or exchange in the index, if staging fails the step is reverted.
+    fn stage(&mut self, step: &Step, inverse: &Step) -> io::Result<()> {
+        let Some(git) = &self.git else {
+            return Ok(());
+        };
+
+        let res = match step {
+            Step::Rename { from, to } => git.rename(from, to),
+            Step::Exchange { a, b } => git.exchange(a, b),
+            _ => Ok(()),
+        };
+
+        if let Err(err) = res {
+            inverse.apply(&mut self.fs)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Applies the inverse of a step, renames never replace existing paths.
+    fn revert(&mut self, inverse: &Step) -> io::Result<()> {
+        match inverse {
+            Step::Rename { from, to } => {
+                self.fs.rename(from, to)?;
+                match &self.git {
+                    Some(git) => git.rename(from, to),
+                    None => Ok(()),
+                }
+            }
+            Step::Exchange { a, b } => {
+                inverse.apply(&mut self.fs)?;
+                match &self.git {
+                    Some(git) => git.exchange(a, b),
+                    None => Ok(()),
+                }
+            }
+            step => step.apply(&mut self.fs),
+        }
+    }
+
+    /// Moves `path` aside such that it can be removed on commit and returns the inverse.
+    fn remove_later(&mut self, path: &Path) -> io::Result<Step> {
+        let temp = plan::temp_name(path, &BTreeSet::new(), &self.fs);
+        self.fs.rename(path, &temp)?;
+        self.removals.push(temp.clone());
+
+        Ok(Step::Rename {
+            from: temp,
+            to: path.to_path_buf(),
+        })
+    }
+
+    /// Rolls back all applied steps in reverse order, returning each step with its inverse and
+    /// the result of applying it.
+    pub fn rollback(mut self) -> Vec<(Step, Step, io::Result<()>)> {
+        let applied = std::mem::take(&mut self.applied);
+        applied
+            .into_iter()
+            .rev()
+            .map(|(step, inverse)| {
+                let res = self.revert(&inverse);
+                (step, inverse, res)
+            })
+            .collect()
+    }
+
+    /// Finishes the transaction by removing all paths which were moved aside, returning those
+    /// which could not be removed.
+    pub fn commit(mut self) -> Vec<(PathBuf, io::Error)> {
+        // later removals may contain earlier ones
+        std::mem::take(&mut self.removals)
+            .into_iter()
+            .rev()
+            .filter_map(|path| match self.fs.remove(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Some((path, err)),
+                _ => None,
+            })
+            .collect()
+    }
+}
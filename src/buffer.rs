@@ -0,0 +1,298 @@
+//! Writing and parsing of the edit buffer.
+//!
+//! Each path is written on its own line, prefixed with its 1-based id and a tab. Lines are paired
+//! with the original paths by their id, so they can be reordered freely. Paths are escaped and
+//! quoted if necessary, see [`escape`].
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::escape;
+
+/// The prefix of comments annotating errors.
+const ERROR: &str = "// error: ";
+
+/// Writes the `header` as comments, followed by the `paths` prefixed with their ids.
+pub fn write(buffer: &mut Vec<u8>, header: &[&str], paths: &[PathBuf]) -> io::Result<()> {
+    for line in header {
+        writeln!(buffer, "// {line}")?;
+    }
+    writeln!(buffer)?;
+
+    let width = paths.len().to_string().len();
+    for (idx, path) in paths.iter().enumerate() {
+        writeln!(buffer, "{:0width$}\t{}", idx + 1, escape::display(path))?;
+    }
+
+    Ok(())
+}
+
+/// A valid line of a parsed edit buffer.
+#[derive(Debug)]
+pub struct Entry {
+    /// The 1-based line number.
+    pub line: usize,
+
+    /// The 1-based id.
+    pub id: usize,
+
+    /// The unescaped path.
+    pub path: PathBuf,
+}
+
+/// The lines of a parsed edit buffer.
+#[derive(Debug, Default)]
+pub struct Parsed {
+    /// All valid lines in order of appearance.
+    pub entries: Vec<Entry>,
+
+    /// The 1-based line numbers and contents of all lines without an id or a valid path.
+    pub invalid: Vec<(usize, String)>,
+}
+
+/// The paths of an edit buffer paired with the original paths by their ids.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Paired {
+    /// The paths of each id, the first one is its new path.
+    pub after: Vec<Vec<PathBuf>>,
+
+    /// The 1-based line numbers of the paths of each id.
+    pub lines: Vec<Vec<usize>>,
+
+    /// The 1-based line numbers and ids of all lines whose id has no original path.
+    pub unknown: Vec<(usize, usize)>,
+}
+
+impl Parsed {
+    /// Pairs the entries with the `shown` paths by their ids.
+    ///
+    /// The first path of an id is its new path, unless the shown path was kept, then that one is
+    /// moved to the front, such that the other paths are copies of it.
+    pub fn pair(self, shown: &[PathBuf]) -> Paired {
+        let mut paired = Paired {
+            after: vec![vec![]; shown.len()],
+            lines: vec![vec![]; shown.len()],
+            unknown: vec![],
+        };
+
+        for entry in self.entries {
+            match entry.id.checked_sub(1).filter(|&idx| idx < shown.len()) {
+                Some(idx) => {
+                    paired.after[idx].push(entry.path);
+                    paired.lines[idx].push(entry.line);
+                }
+                None => paired.unknown.push((entry.line, entry.id)),
+            }
+        }
+
+        for (idx, paths) in paired.after.iter_mut().enumerate() {
+            if let Some(nth) = paths.iter().position(|path| *path == shown[idx]) {
+                paths.swap(0, nth);
+                paired.lines[idx].swap(0, nth);
+            }
+        }
+
+        paired
+    }
+}
+
+/// Parses an edit buffer, empty lines and comments are ignored.
+///
+/// Lines don't have to be valid UTF-8, bytes which are not are taken as is.
+pub fn parse(buffer: &[u8]) -> Parsed {
+    let mut parsed = Parsed::default();
+
+    for (idx, line) in buffer.split(|&byte| byte == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        if line.is_empty() || line.starts_with(b"//") {
+            continue;
+        }
+
+        let entry = line
+            .iter()
+            .position(|&byte| byte == b'\t' || byte == b' ')
+            .and_then(|sep| {
+                let id = std::str::from_utf8(&line[..sep]).ok()?.parse().ok()?;
+                let path = escape::unquote(&line[sep + 1..])?;
+                Some(Entry {
+                    line: idx + 1,
+                    id,
+                    path: PathBuf::from(path),
+                })
+            });
+
+        match entry {
+            Some(entry) => parsed.entries.push(entry),
+            None => parsed
+                .invalid
+                .push((idx + 1, String::from_utf8_lossy(line).into_owned())),
+        }
+    }
+
+    parsed
+}
+
+/// Writes the `paths` one per line without ids, such that they can be paired by position.
+pub fn write_list(buffer: &mut Vec<u8>, paths: &[PathBuf]) -> io::Result<()> {
+    for path in paths {
+        writeln!(buffer, "{}", escape::display(path))?;
+    }
+
+    Ok(())
+}
+
+/// Parses a list of paths written by [`write_list`], the ids are the 1-based line numbers.
+///
+/// Unlike the edit buffer, empty lines and comments are not ignored but invalid.
+pub fn parse_list(buffer: &[u8]) -> Parsed {
+    let mut parsed = Parsed::default();
+    let buffer = buffer.strip_suffix(b"\n").unwrap_or(buffer);
+    if buffer.is_empty() {
+        return parsed;
+    }
+
+    for (idx, line) in buffer.split(|&byte| byte == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        match escape::unquote(line) {
+            Some(path) => parsed.entries.push(Entry {
+                line: idx + 1,
+                id: idx + 1,
+                path: PathBuf::from(path),
+            }),
+            None => parsed
+                .invalid
+                .push((idx + 1, String::from_utf8_lossy(line).into_owned())),
+        }
+    }
+
+    parsed
+}
+
+/// Replaces the error comments of an edited buffer, `general` errors are inserted at the top and
+/// `errors` above the line with the given 1-based number.
+pub fn annotate(
+    buffer: &[u8],
+    general: &[String],
+    errors: &BTreeMap<usize, Vec<String>>,
+) -> Vec<u8> {
+    let mut annotated = vec![];
+    for error in general {
+        annotated.extend(format!("{ERROR}{error}\n").as_bytes());
+    }
+
+    let buffer = buffer.strip_suffix(b"\n").unwrap_or(buffer);
+    for (idx, line) in buffer.split(|&byte| byte == b'\n').enumerate() {
+        if line.starts_with(ERROR.as_bytes()) {
+            continue;
+        }
+
+        for error in errors.get(&(idx + 1)).into_iter().flatten() {
+            annotated.extend(format!("{ERROR}{error}\n").as_bytes());
+        }
+
+        annotated.extend(line);
+        annotated.push(b'\n');
+    }
+
+    annotated
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    use super::*;
+
+    fn paths() -> Vec<PathBuf> {
+        [
+            &b"a.txt"[..],
+            b"dir/b c",
+            b" leading",
+            b"new\nline",
+            b"\xFF",
+        ]
+        .into_iter()
+        .map(|bytes| PathBuf::from(OsString::from_vec(bytes.to_vec())))
+        .collect()
+    }
+
+    #[test]
+    fn buffer_round_trips() {
+        let paths = paths();
+        let mut buffer = vec![];
+        write(&mut buffer, &["a header"], &paths).unwrap();
+
+        let parsed = parse(&buffer);
+        assert!(parsed.invalid.is_empty());
+
+        let parsed: Vec<_> = parsed
+            .entries
+            .into_iter()
+            .map(|entry| (entry.id, entry.path))
+            .collect();
+        let expected: Vec<_> = paths
+            .into_iter()
+            .enumerate()
+            .map(|(idx, path)| (idx + 1, path))
+            .collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn lines_are_paired_by_id() {
+        let parsed = parse(b"// comment\n\n2\tb\n1 a\r\n2\tc\nno id\n3\t\"open\n");
+
+        let entries: Vec<_> = parsed
+            .entries
+            .iter()
+            .map(|entry| (entry.line, entry.id, entry.path.to_str().unwrap()))
+            .collect();
+        assert_eq!(entries, [(3, 2, "b"), (4, 1, "a"), (5, 2, "c")]);
+
+        let invalid: Vec<_> = parsed.invalid.iter().map(|(line, _)| *line).collect();
+        assert_eq!(invalid, [6, 7]);
+    }
+
+    #[test]
+    fn kept_paths_are_paired_first() {
+        let shown = [PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")];
+        let parsed = parse(b"1\tx\n1\ta\n0\ty\n2\tz\n4\tw\n");
+
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            parsed.pair(&shown),
+            Paired {
+                after: vec![paths(&["a", "x"]), paths(&["z"]), vec![]],
+                lines: vec![vec![2, 1], vec![4], vec![]],
+                unknown: vec![(3, 0), (5, 4)],
+            }
+        );
+    }
+
+    #[test]
+    fn list_round_trips() {
+        let paths = paths();
+        let mut buffer = vec![];
+        write_list(&mut buffer, &paths).unwrap();
+
+        let parsed = parse_list(&buffer);
+        assert!(parsed.invalid.is_empty());
+
+        let parsed: Vec<_> = parsed.entries.into_iter().map(|entry| entry.path).collect();
+        assert_eq!(parsed, paths);
+    }
+
+    #[test]
+    fn annotations_are_replaced() {
+        let errors = BTreeMap::from([(2, vec!["bad".to_owned()])]);
+        let annotated = annotate(b"1\ta\n2\tb\n", &["general".into()], &errors);
+        assert_eq!(annotated, b"// error: general\n1\ta\n// error: bad\n2\tb\n");
+
+        let cleared = annotate(&annotated, &[], &BTreeMap::new());
+        assert_eq!(cleared, b"1\ta\n2\tb\n");
+    }
+}
@@ -0,0 +1,334 @@
+//! Creating copies and links of paths.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use crate::sys;
+
+/// How copies of paths are created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CopyMode {
+    /// Copy the contents of files.
+    #[default]
+    Copy,
+
+    /// Create hard links to files, directories are recreated.
+    Hardlink,
+
+    /// Create a relative symbolic link to the path.
+    Symlink,
+
+    /// Copy the contents of files using reflinks, falls back to regular copies if the
+    /// filesystem doesn't support them.
+    Reflink,
+}
+
+/// Returns `path` relative to the directory `base`, both are made absolute first.
+pub fn relative_to(path: &Path, base: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    let base = std::path::absolute(base)?;
+
+    let path: Vec<_> = path
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    let base: Vec<_> = base
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+
+    let common = Iterator::zip(path.iter(), base.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &path[common..] {
+        relative.push(component);
+    }
+
+    Ok(relative)
+}
+
+/// Copies the contents and permissions of the file `from` to the new file `to`.
+fn copy_file(from: &Path, to: &Path, reflink: bool) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let mode = source.metadata()?.permissions().mode();
+    let mut target = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(to)?;
+
+    let res = match reflink {
+        true => sys::reflink(&source, &target),
+        false => Err(io::ErrorKind::Unsupported.into()),
+    };
+
+    if res.is_err() {
+        io::copy(&mut source, &mut target)?;
+    }
+
+    target.set_permissions(fs::Permissions::from_mode(mode))
+}
+
+/// Creates `to` from `from` using the given mode, directories are copied recursively.
+///
+/// Existing paths are never replaced.
+pub fn copy(from: &Path, to: &Path, mode: CopyMode) -> io::Result<()> {
+    if mode == CopyMode::Symlink {
+        let ancestor = to.parent().unwrap_or(Path::new(""));
+        return std::os::unix::fs::symlink(relative_to(from, ancestor)?, to);
+    }
+
+    let meta = fs::symlink_metadata(from)?;
+    if meta.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)
+    } else if meta.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let name = entry?.file_name();
+            copy(&from.join(&name), &to.join(&name), mode)?;
+        }
+
+        fs::set_permissions(to, meta.permissions())
+    } else {
+        match mode {
+            CopyMode::Copy => copy_file(from, to, false),
+            CopyMode::Reflink => copy_file(from, to, true),
+            CopyMode::Hardlink => fs::hard_link(from, to),
+            CopyMode::Symlink => unreachable!(),
+        }
+    }
+}
+
+/// Returns the device of `path` or its nearest existing ancestor.
+fn device(path: &Path) -> Option<u64> {
+    path.ancestors()
+        .map(|ancestor| match ancestor.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => ancestor,
+        })
+        .find_map(|ancestor| fs::symlink_metadata(ancestor).ok())
+        .map(|meta| meta.dev())
+}
+
+/// Returns whether renaming `from` to `to` would cross filesystems and must fall back to
+/// copying.
+pub fn crosses_devices(from: &Path, to: &Path) -> bool {
+    let to = to.parent().unwrap_or(Path::new(""));
+    match (device(from), device(to)) {
+        (Some(from), Some(to)) => from != to,
+        _ => false,
+    }
+}
+
+/// Copies `from` to `to` recursively, preserving permissions, ownership where possible,
+/// timestamps and extended attributes. Files and directories are synced once written.
+fn copy_preserving(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let file_type = meta.file_type();
+
+    if file_type.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else if file_type.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let name = entry?.file_name();
+            copy_preserving(&from.join(&name), &to.join(&name))?;
+        }
+
+        fs::set_permissions(to, meta.permissions())?;
+        File::open(to)?.sync_all()?;
+    } else if file_type.is_file() {
+        let mut source = File::open(from)?;
+        let mut target = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(meta.mode())
+            .open(to)?;
+
+        io::copy(&mut source, &mut target)?;
+        target.set_permissions(meta.permissions())?;
+        target.sync_all()?;
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't copy special file {}", from.display()),
+        ));
+    }
+
+    // changing ownership is only possible with privileges
+    match std::os::unix::fs::lchown(to, Some(meta.uid()), Some(meta.gid())) {
+        Err(err) if err.kind() != io::ErrorKind::PermissionDenied => return Err(err),
+        _ => {}
+    }
+
+    sys::copy_xattrs(from, to)?;
+
+    // times are set last, as any other change would update them
+    sys::copy_times(&meta, to)
+}
+
+/// Moves `from` to `to` on another filesystem by copying it, the source is only removed once
+/// the copy was fully written and synced. A partial copy is removed if copying fails.
+pub fn move_across(from: &Path, to: &Path) -> io::Result<()> {
+    if let Err(err) = copy_preserving(from, to) {
+        let _ = match fs::symlink_metadata(to) {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(to),
+            Ok(_) => fs::remove_file(to),
+            Err(_) => Ok(()),
+        };
+
+        return Err(err);
+    }
+
+    let ancestor = match to.parent() {
+        Some(ancestor) if !ancestor.as_os_str().is_empty() => ancestor,
+        _ => Path::new("."),
+    };
+    File::open(ancestor)?.sync_all()?;
+
+    if fs::symlink_metadata(from)?.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("evaki-copy-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn relative_paths() {
+        let relative = |path, base| relative_to(Path::new(path), Path::new(base)).unwrap();
+
+        assert_eq!(relative("/a/b/c", "/a"), Path::new("b/c"));
+        assert_eq!(relative("/a/b", "/a/c/d"), Path::new("../../b"));
+        assert_eq!(relative("/a/./b", "/a/b"), Path::new(""));
+        assert_eq!(relative("/x", "/a/b"), Path::new("../../x"));
+    }
+
+    #[test]
+    fn symlinks_are_relative() {
+        let dir = temp_dir("symlink");
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::create_dir_all(dir.join("links")).unwrap();
+        fs::write(dir.join("src/nested/file"), "content").unwrap();
+
+        let link = dir.join("links/file");
+        copy(&dir.join("src/nested/file"), &link, CopyMode::Symlink).unwrap();
+        assert_eq!(
+            fs::read_link(&link).unwrap(),
+            Path::new("../src/nested/file")
+        );
+        assert_eq!(fs::read_to_string(&link).unwrap(), "content");
+
+        // existing paths are never replaced
+        let err = copy(&dir.join("src/nested"), &link, CopyMode::Symlink).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn directories_are_copied_recursively() {
+        let dir = temp_dir("recursive");
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::write(dir.join("src/nested/file"), "content").unwrap();
+        std::os::unix::fs::symlink("nested/file", dir.join("src/link")).unwrap();
+
+        for (mode, name) in [(CopyMode::Copy, "copy"), (CopyMode::Hardlink, "hardlink")] {
+            let to = dir.join(name);
+            copy(&dir.join("src"), &to, mode).unwrap();
+            assert_eq!(
+                fs::read_to_string(to.join("nested/file")).unwrap(),
+                "content"
+            );
+
+            // symlinks inside of copies are kept as they are
+            assert_eq!(
+                fs::read_link(to.join("link")).unwrap(),
+                Path::new("nested/file")
+            );
+        }
+
+        let original = fs::metadata(dir.join("src/nested/file")).unwrap();
+        let hardlink = fs::metadata(dir.join("hardlink/nested/file")).unwrap();
+        assert_eq!(original.ino(), hardlink.ino());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn moves_across_devices_keep_metadata() {
+        let dir = temp_dir("move");
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::write(dir.join("src/nested/file"), "content").unwrap();
+        std::os::unix::fs::symlink("nested/file", dir.join("src/link")).unwrap();
+
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        let file = File::options()
+            .write(true)
+            .open(dir.join("src/nested/file"))
+            .unwrap();
+        file.set_permissions(fs::Permissions::from_mode(0o640))
+            .unwrap();
+        file.set_modified(modified).unwrap();
+        fs::set_permissions(dir.join("src/nested"), fs::Permissions::from_mode(0o750)).unwrap();
+
+        // renames within the same directory never cross devices, the copy is forced here
+        assert!(!crosses_devices(&dir.join("src"), &dir.join("dst")));
+        move_across(&dir.join("src"), &dir.join("dst")).unwrap();
+        assert!(!dir.join("src").exists());
+
+        let meta = fs::metadata(dir.join("dst/nested/file")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+        assert_eq!(meta.modified().unwrap(), modified);
+        assert_eq!(
+            fs::read_to_string(dir.join("dst/nested/file")).unwrap(),
+            "content"
+        );
+
+        let meta = fs::metadata(dir.join("dst/nested")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o750);
+        assert_eq!(
+            fs::read_link(dir.join("dst/link")).unwrap(),
+            Path::new("nested/file")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_moves_keep_the_source() {
+        let dir = temp_dir("failed");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/file"), "content").unwrap();
+
+        // special files can't be copied, so the partial copy is removed again
+        let fifo =
+            std::ffi::CString::new(dir.join("src/fifo").as_os_str().as_encoded_bytes()).unwrap();
+        // SAFETY: the path is a valid nul terminated string
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        let err = move_across(&dir.join("src"), &dir.join("dst")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(!dir.join("dst").exists());
+        assert_eq!(fs::read_to_string(dir.join("src/file")).unwrap(), "content");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
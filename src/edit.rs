@@ -0,0 +1,387 @@
+//! Editing the paths in an editor or piping them through a filter.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use evaki::buffer::{self, Paired};
+use evaki::copy::CopyMode;
+use evaki::vfs::Os;
+use evaki::{escape, RenamePlan};
+
+use crate::session::Session;
+use crate::{report, Args};
+
+/// Appends the hidden extension of each id to its paths.
+pub fn append_extensions(after: Vec<Vec<PathBuf>>, extensions: &[&OsStr]) -> Vec<Vec<PathBuf>> {
+    Iterator::zip(after.into_iter(), extensions)
+        .map(|(paths, extension)| {
+            paths
+                .into_iter()
+                .map(|path| {
+                    let mut path = path.into_os_string();
+                    path.push(extension);
+                    PathBuf::from(path)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the words of the editor command, `editor` takes precedence over the environment
+/// variables looked up by `var`. Returns a message if the command is empty or can't be split.
+fn editor_command(
+    editor: Option<&str>,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<String>, String> {
+    let editor = editor
+        .map(str::to_owned)
+        .or_else(|| {
+            ["EVAKI_EDITOR", "VISUAL", "EDITOR"]
+                .into_iter()
+                .filter_map(var)
+                .find(|editor| !editor.trim().is_empty())
+        })
+        .unwrap_or_else(|| "vi".to_owned());
+
+    match shell_words::split(&editor) {
+        Ok(words) if !words.is_empty() => Ok(words),
+        Ok(_) => Err("the editor command is empty".into()),
+        Err(err) => Err(format!("invalid editor command `{editor}`: {err}")),
+    }
+}
+
+/// Returns a new edit buffer for the `shown` paths.
+fn new_buffer(args: &Args, shown: &[PathBuf]) -> io::Result<Vec<u8>> {
+    let copy_mode = args.copy_mode();
+
+    let mut header = vec![
+        "empty lines and comments are ignored",
+        "lines may be reordered, but do not change their ids",
+    ];
+    match copy_mode {
+        None => header.push("duplicated lines copy their file"),
+        Some(CopyMode::Copy | CopyMode::Reflink) => {
+            header.push("edited and duplicated lines copy their file")
+        }
+        Some(CopyMode::Hardlink | CopyMode::Symlink) => {
+            header.push("edited and duplicated lines link their file")
+        }
+    }
+    if copy_mode.is_some() {
+        header.push("removed lines are skipped");
+    } else if args.delete {
+        if args.no_trash {
+            header.push("removed lines permanently delete their file");
+        } else {
+            header.push("removed lines move their file to the trash");
+        }
+    } else {
+        header.push("do not remove any lines");
+    }
+    if args.move_files {
+        header.push("do not edit anything other than file paths");
+    } else {
+        header.push("do not edit anything other than file stems");
+    }
+    if args.hide_extensions {
+        header.push("extensions of files are hidden and kept");
+    }
+
+    if shown
+        .iter()
+        .any(|path| escape::display(path).as_bytes() != path.as_os_str().as_bytes())
+    {
+        header.push("paths may be quoted, \\\\ \\\" \\t \\n and \\xNN are escapes");
+    }
+
+    let mut buffer = vec![];
+    buffer::write(&mut buffer, &header, shown)?;
+
+    Ok(buffer)
+}
+
+/// The paths of each id after editing, with the session of the edit buffer.
+pub type Edited = (Vec<Vec<PathBuf>>, Session);
+
+/// Lets the user edit the `shown` paths in the editor, returns the paths of each id with the
+/// session of the edit buffer, or `None` if editing was aborted.
+///
+/// The `extensions` hidden in `shown` are appended again and the paths are validated against
+/// `before`. If they are invalid, the editor is reopened with the errors annotated, unless the
+/// buffer is left as it is. A `resumed` buffer is validated before the editor is opened. If
+/// `stdin_read` is set, the editor is connected to the terminal instead of stdin.
+pub fn edit(
+    args: &Args,
+    before: &[PathBuf],
+    shown: &[PathBuf],
+    extensions: &[&OsStr],
+    resumed: Option<Session>,
+    stdin_read: bool,
+) -> Result<Option<Edited>, Box<dyn Error>> {
+    let words = match editor_command(args.editor.as_deref(), |var| std::env::var(var).ok()) {
+        Ok(words) => words,
+        Err(message) => {
+            eprintln!("{message}");
+            return Ok(None);
+        }
+    };
+
+    let mut open_editor = resumed.is_none();
+    let (session, mut buffer) = match resumed {
+        Some(session) => {
+            let buffer = std::fs::read(&session.buffer)?;
+            (session, buffer)
+        }
+        None => {
+            let buffer = new_buffer(args, shown)?;
+            (
+                Session::create(before, &buffer, args.buffer_flags())?,
+                buffer,
+            )
+        }
+    };
+
+    loop {
+        if open_editor {
+            let (stdin, stdout) = match editor_stdio(stdin_read) {
+                Ok(stdio) => stdio,
+                Err(err) => {
+                    eprintln!("failed to open the terminal for the editor: {err}");
+                    eprintln!("the files were read from stdin, so the editor needs a terminal");
+                    keep(&session);
+                    return Ok(None);
+                }
+            };
+
+            let status = match Command::new(&words[0])
+                .args(&words[1..])
+                .arg(&session.buffer)
+                .stdin(stdin)
+                .stdout(stdout)
+                .stderr(Stdio::inherit())
+                .status()
+            {
+                Ok(status) => status,
+                Err(err) => {
+                    eprintln!("failed to run editor `{}`: {err}", words[0]);
+                    eprintln!("pass --editor or set EVAKI_EDITOR, VISUAL or EDITOR");
+                    keep(&session);
+                    return Ok(None);
+                }
+            };
+
+            if !status.success() {
+                eprintln!("editor exited with: {status}");
+                keep(&session);
+                return Ok(None);
+            }
+        }
+
+        let edited = std::fs::read(&session.buffer)?;
+        let mut parsed = buffer::parse(&edited);
+
+        if parsed.entries.is_empty() && parsed.invalid.is_empty() {
+            eprintln!("the buffer is empty, aborting");
+            session.remove()?;
+            return Ok(None);
+        }
+
+        let mut errors: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        let invalid = std::mem::take(&mut parsed.invalid);
+        for (line, _) in &invalid {
+            errors
+                .entry(*line)
+                .or_default()
+                .push("line without an id or a valid path".into());
+        }
+
+        // pair paths by their ids
+        let Paired {
+            after,
+            lines,
+            unknown,
+        } = parsed.pair(shown);
+
+        for &(line, id) in &unknown {
+            errors
+                .entry(line)
+                .or_default()
+                .push(format!("unknown id {id}"));
+        }
+
+        let after = append_extensions(after, extensions);
+
+        let mut general = vec![];
+        let problems = RenamePlan::new(before.to_vec(), after.clone(), args.plan_options())
+            .validate(&Os)
+            .problems;
+
+        if errors.is_empty() && problems.is_empty() {
+            return Ok(Some((after, session)));
+        }
+
+        for problem in &problems {
+            for (at, message) in problem.annotations(before) {
+                match at {
+                    Some((idx, nth)) => errors.entry(lines[idx][nth]).or_default().push(message),
+                    None => general.push(message),
+                }
+            }
+        }
+
+        // the editor can't fix anything if it only changes annotations
+        let unannotated = |buffer| buffer::annotate(buffer, &[], &BTreeMap::new());
+        if open_editor && unannotated(&edited) == unannotated(&buffer) {
+            if !invalid.is_empty() {
+                eprintln!("lines without an id or a valid path:");
+                for (line, content) in invalid {
+                    eprintln!("{line}: {content}");
+                }
+            }
+
+            let unknown_ids: BTreeSet<_> = unknown.iter().map(|&(_, id)| id).collect();
+            if !unknown_ids.is_empty() {
+                eprintln!("unknown ids:");
+                for id in unknown_ids {
+                    eprintln!("{id}");
+                }
+            }
+
+            report::problems(&problems, before, &after);
+            keep(&session);
+            return Ok(None);
+        }
+
+        buffer = buffer::annotate(&edited, &general, &errors);
+        std::fs::write(&session.buffer, &buffer)?;
+        open_editor = true;
+    }
+}
+
+/// Returns the stdin and stdout of the editor.
+///
+/// If `stdin_read` is set, stdin is replaced by the terminal, as is stdout if it is redirected.
+fn editor_stdio(stdin_read: bool) -> io::Result<(Stdio, Stdio)> {
+    if !stdin_read {
+        return Ok((Stdio::inherit(), Stdio::inherit()));
+    }
+
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let stdout = match io::stdout().is_terminal() {
+        true => Stdio::inherit(),
+        false => Stdio::from(tty.try_clone()?),
+    };
+
+    Ok((Stdio::from(tty), stdout))
+}
+
+/// Tells the user how to continue with a kept edit buffer.
+pub fn keep(session: &Session) {
+    eprintln!(
+        "the edit buffer was kept, pass --resume {} to continue",
+        escape::display(&session.buffer)
+    );
+}
+
+/// Pipes `before` through the filter `command`, returns the paths of each id or `None` if the
+/// command failed or its output could not be paired with `before`.
+pub fn filter(
+    command: &str,
+    before: &[PathBuf],
+) -> Result<Option<Vec<Vec<PathBuf>>>, Box<dyn Error>> {
+    let mut input = vec![];
+    buffer::write_list(&mut input, before)?;
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    // write on another thread, such that a filter producing output early doesn't block
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+
+    // filters may exit without reading all of their input
+    match writer.join().expect("writing to the filter doesn't panic") {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err.into()),
+        _ => {}
+    }
+
+    if !output.status.success() {
+        eprintln!("filter exited with: {}", output.status);
+        return Ok(None);
+    }
+
+    let parsed = buffer::parse_list(&output.stdout);
+
+    if !parsed.invalid.is_empty() {
+        eprintln!("filter output lines without a valid path:");
+        for (line, content) in parsed.invalid {
+            eprintln!("{line}: {content}");
+        }
+
+        return Ok(None);
+    }
+
+    if parsed.entries.len() != before.len() {
+        eprintln!(
+            "filter output {} line(s), but {} were expected",
+            parsed.entries.len(),
+            before.len()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(
+        parsed
+            .entries
+            .into_iter()
+            .map(|entry| vec![entry.path])
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editor_commands() {
+        let env = |vars: &'static [(&str, &str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        assert_eq!(editor_command(None, env(&[])), Ok(vec!["vi".into()]));
+        assert_eq!(
+            editor_command(None, env(&[("EDITOR", "nano"), ("VISUAL", "code --wait")])),
+            Ok(vec!["code".into(), "--wait".into()])
+        );
+
+        // empty variables are skipped
+        assert_eq!(
+            editor_command(None, env(&[("EVAKI_EDITOR", "  "), ("EDITOR", "nano")])),
+            Ok(vec!["nano".into()])
+        );
+        assert_eq!(
+            editor_command(Some("'my editor' -f"), env(&[("EVAKI_EDITOR", "nano")])),
+            Ok(vec!["my editor".into(), "-f".into()])
+        );
+
+        assert!(editor_command(Some(""), env(&[])).is_err());
+        assert!(editor_command(Some("vim 'unclosed"), env(&[])).is_err());
+    }
+}
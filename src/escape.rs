@@ -0,0 +1,182 @@
+//! Reversible escaping of paths as text.
+//!
+//! Backslashes are escaped as `\\`, tabs and newlines as `\t` and `\n`, other control characters
+//! and bytes which are not valid UTF-8 as `\xNN`, such that any path can be shown and edited
+//! losslessly. The letters of escapes are case-insensitive, such that changing the case of escaped
+//! text, e.g. by a filter, doesn't change the escapes.
+//!
+//! Paths which are empty, start or end with whitespace, or start with `"` or `//` are quoted, in
+//! quoted paths `"` is escaped as `\"`.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+/// Escapes a path.
+pub fn escape(path: &OsStr) -> String {
+    let mut escaped = String::new();
+    for chunk in path.as_bytes().utf8_chunks() {
+        for char in chunk.valid().chars() {
+            match char {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                _ if char.is_control() => {
+                    let mut bytes = [0; 4];
+                    for byte in char.encode_utf8(&mut bytes).bytes() {
+                        escaped.push_str(&format!("\\x{byte:02X}"));
+                    }
+                }
+                _ => escaped.push(char),
+            }
+        }
+
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{byte:02X}"));
+        }
+    }
+
+    escaped
+}
+
+/// Reverses [`escape`], returns `None` for invalid escapes.
+pub fn unescape(escaped: &[u8]) -> Option<OsString> {
+    unescape_impl(escaped, false)
+}
+
+fn unescape_impl(escaped: &[u8], quoted: bool) -> Option<OsString> {
+    let mut unescaped = vec![];
+    let mut bytes = escaped.iter().copied();
+    while let Some(byte) = bytes.next() {
+        if quoted && byte == b'"' {
+            return None;
+        }
+
+        if byte != b'\\' {
+            unescaped.push(byte);
+            continue;
+        }
+
+        match bytes.next()? {
+            b'\\' => unescaped.push(b'\\'),
+            b'"' if quoted => unescaped.push(b'"'),
+            b't' | b'T' => unescaped.push(b'\t'),
+            b'n' | b'N' => unescaped.push(b'\n'),
+            b'x' | b'X' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                unescaped.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            _ => return None,
+        }
+    }
+
+    Some(OsString::from_vec(unescaped))
+}
+
+/// Escapes a path and quotes it, if it could not be told apart from surrounding text otherwise.
+pub fn quote(path: &OsStr) -> String {
+    let escaped = escape(path);
+    let needs_quotes = escaped.is_empty()
+        || escaped.starts_with(char::is_whitespace)
+        || escaped.ends_with(char::is_whitespace)
+        || escaped.starts_with('"')
+        || escaped.starts_with("//");
+
+    match needs_quotes {
+        true => format!("\"{}\"", escaped.replace('"', "\\\"")),
+        false => escaped,
+    }
+}
+
+/// Reverses [`quote`], surrounding ASCII whitespace is ignored. Returns `None` for invalid
+/// escapes, unterminated quotes and empty paths.
+pub fn unquote(text: &[u8]) -> Option<OsString> {
+    let text = text.trim_ascii();
+    let path = match text.strip_prefix(b"\"") {
+        Some(quoted) => unescape_impl(quoted.strip_suffix(b"\"")?, true)?,
+        None => unescape(text)?,
+    };
+
+    match path.is_empty() {
+        true => None,
+        false => Some(path),
+    }
+}
+
+/// Escapes and quotes a path for display.
+pub fn display(path: &Path) -> String {
+    quote(path.as_os_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths() -> Vec<OsString> {
+        [
+            &b"plain.txt"[..],
+            b"dir/with space",
+            b"back\\slash",
+            b"tab\there",
+            b"new\nline",
+            b"bell\x07",
+            b"invalid \xFF utf-8",
+            b" leading",
+            b"trailing ",
+            b"\"quoted\"",
+            b"//comment",
+            b"caf\xC3\xA9",
+        ]
+        .into_iter()
+        .map(|bytes| OsString::from_vec(bytes.to_vec()))
+        .collect()
+    }
+
+    #[test]
+    fn escape_round_trips() {
+        for path in paths() {
+            let escaped = escape(&path);
+            assert!(!escaped.contains(['\t', '\n']), "{escaped}");
+            assert_eq!(unescape(escaped.as_bytes()), Some(path));
+        }
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        for path in paths() {
+            let quoted = quote(&path);
+            assert_eq!(unquote(quoted.as_bytes()), Some(path.clone()), "{quoted}");
+            assert_eq!(unquote(format!("  {quoted} ").as_bytes()), Some(path));
+        }
+    }
+
+    #[test]
+    fn quotes_only_when_necessary() {
+        assert_eq!(quote(OsStr::new("a b")), "a b");
+        assert_eq!(quote(OsStr::new(" a")), "\" a\"");
+        assert_eq!(quote(OsStr::new("\"a")), "\"\\\"a\"");
+        assert_eq!(quote(OsStr::new("//a")), "\"//a\"");
+        assert_eq!(quote(OsStr::new("a\tb")), "a\\tb");
+    }
+
+    #[test]
+    fn escapes_are_case_insensitive() {
+        for path in paths() {
+            let upper = escape(&path).to_ascii_uppercase();
+            let expected = OsString::from_vec(path.as_bytes().to_ascii_uppercase());
+            assert_eq!(unescape(upper.as_bytes()), Some(expected), "{upper}");
+        }
+    }
+
+    #[test]
+    fn invalid_text_is_refused() {
+        assert_eq!(unescape(b"a\\"), None);
+        assert_eq!(unescape(b"a\\q"), None);
+        assert_eq!(unescape(b"a\\xZZ"), None);
+        assert_eq!(unquote(b"\"a"), None);
+        assert_eq!(unquote(b"\"a\"b\""), None);
+        assert_eq!(unquote(b"\"\""), None);
+        assert_eq!(unquote(b"   "), None);
+    }
+}
@@ -0,0 +1,403 @@
+//! Machine readable output of plans instead of applying them.
+//!
+//! Paths in JSON and CSV are escaped like in the edit buffer, but never quoted. Ids start at 1.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use evaki::copy::{self, CopyMode};
+use evaki::plan::{self, Step};
+use evaki::validate::{Diagnostics, Location};
+use evaki::vfs::Os;
+use evaki::{escape, RenamePlan};
+
+/// The format plans are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// A JSON object with the entries, their status and diagnostics, and the steps.
+    Json,
+
+    /// CSV with one row per entry.
+    Csv,
+
+    /// A shell script applying the steps, paths which would be trashed are removed.
+    Sh,
+}
+
+/// A path after of an id, or the path before if the id has no paths after.
+struct Entry<'a> {
+    id: usize,
+    before: &'a Path,
+    after: Option<&'a Path>,
+    status: &'static str,
+
+    /// The severity and message of each diagnostic.
+    diagnostics: Vec<(&'static str, String)>,
+}
+
+/// Returns the entries of `plan` and the diagnostics which don't refer to an entry.
+fn entries<'a>(
+    plan: &'a RenamePlan,
+    diagnostics: &Diagnostics,
+) -> (Vec<Entry<'a>>, Vec<(&'static str, String)>) {
+    let copy = plan.options().copy_mode.is_some();
+
+    let mut entries = vec![];
+    let mut locations: BTreeMap<Location, usize> = BTreeMap::new();
+
+    for (idx, (before, paths)) in Iterator::zip(plan.before().iter(), plan.after()).enumerate() {
+        if paths.is_empty() {
+            entries.push(Entry {
+                id: idx,
+                before,
+                after: None,
+                status: if copy { "skipped" } else { "removed" },
+                diagnostics: vec![],
+            });
+        }
+
+        for (nth, after) in paths.iter().enumerate() {
+            let status = match (copy, nth) {
+                _ if after == before => "unchanged",
+                (false, 0) => "renamed",
+                _ => "copied",
+            };
+
+            locations.insert((idx, nth), entries.len());
+            entries.push(Entry {
+                id: idx,
+                before,
+                after: Some(after),
+                status,
+                diagnostics: vec![],
+            });
+        }
+    }
+
+    let mut general = vec![];
+    for problem in &diagnostics.problems {
+        for (at, message) in problem.annotations(plan.before()) {
+            match at.and_then(|at| locations.get(&at)) {
+                Some(&entry) => entries[entry].diagnostics.push(("error", message)),
+                None => general.push(("error", message)),
+            }
+        }
+    }
+
+    for warning in &diagnostics.warnings {
+        for (at, message) in warning.annotations() {
+            match locations.get(&at) {
+                Some(&entry) => entries[entry].diagnostics.push(("warning", message)),
+                None => general.push(("warning", message)),
+            }
+        }
+    }
+
+    (entries, general)
+}
+
+/// Writes `plan` with its `diagnostics` and `steps` to `out`.
+pub fn write(
+    format: Format,
+    out: &mut impl Write,
+    plan: &RenamePlan,
+    diagnostics: &Diagnostics,
+    steps: &[Step],
+) -> io::Result<()> {
+    match format {
+        Format::Json => write_json(out, plan, diagnostics, steps),
+        Format::Csv => write_csv(out, plan, diagnostics),
+        Format::Sh => write_sh(out, steps),
+    }
+}
+
+/// Returns `path` as text.
+fn text(path: &Path) -> String {
+    escape::escape(path.as_os_str())
+}
+
+/// Returns `text` as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut string = String::from("\"");
+    for char in text.chars() {
+        match char {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\t' => string.push_str("\\t"),
+            _ if char.is_control() => string.push_str(&format!("\\u{:04x}", char as u32)),
+            _ => string.push(char),
+        }
+    }
+
+    string.push('"');
+    string
+}
+
+/// Returns the diagnostics as a JSON array.
+fn json_diagnostics(diagnostics: &[(&str, String)]) -> String {
+    let diagnostics: Vec<_> = diagnostics
+        .iter()
+        .map(|(severity, message)| {
+            format!(
+                "{{\"severity\": {}, \"message\": {}}}",
+                json_string(severity),
+                json_string(message)
+            )
+        })
+        .collect();
+
+    format!("[{}]", diagnostics.join(", "))
+}
+
+/// Returns a step as a JSON object.
+fn json_step(step: &Step) -> String {
+    let path = |path: &Path| json_string(&text(path));
+    match step {
+        Step::Rename { from, to } => {
+            format!(
+                r#"{{"op": "rename", "from": {}, "to": {}}}"#,
+                path(from),
+                path(to)
+            )
+        }
+        Step::Exchange { a, b } => {
+            format!(
+                r#"{{"op": "exchange", "a": {}, "b": {}}}"#,
+                path(a),
+                path(b)
+            )
+        }
+        Step::Copy { from, to, mode } => {
+            let mode = match mode {
+                CopyMode::Copy => "copy",
+                CopyMode::Hardlink => "hardlink",
+                CopyMode::Symlink => "symlink",
+                CopyMode::Reflink => "reflink",
+            };
+
+            format!(
+                r#"{{"op": "copy", "from": {}, "to": {}, "mode": "{mode}"}}"#,
+                path(from),
+                path(to)
+            )
+        }
+        Step::Remove { path: removed } => {
+            format!(r#"{{"op": "remove", "path": {}}}"#, path(removed))
+        }
+        Step::Trash { path: trashed } => {
+            format!(r#"{{"op": "trash", "path": {}}}"#, path(trashed))
+        }
+        Step::Restore {
+            path: restored,
+            file,
+            ..
+        } => format!(
+            r#"{{"op": "restore", "from": {}, "path": {}}}"#,
+            path(file),
+            path(restored)
+        ),
+        Step::CreateDir { path: created } => {
+            format!(r#"{{"op": "mkdir", "path": {}}}"#, path(created))
+        }
+        Step::RemoveDir { path: removed } => {
+            format!(r#"{{"op": "rmdir", "path": {}}}"#, path(removed))
+        }
+    }
+}
+
+fn write_json(
+    out: &mut impl Write,
+    plan: &RenamePlan,
+    diagnostics: &Diagnostics,
+    steps: &[Step],
+) -> io::Result<()> {
+    let (entries, general) = entries(plan, diagnostics);
+
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"valid\": {},", diagnostics.is_valid())?;
+
+    writeln!(out, "  \"entries\": [")?;
+    for (idx, entry) in entries.iter().enumerate() {
+        let after = match entry.after {
+            Some(after) => json_string(&text(after)),
+            None => "null".into(),
+        };
+
+        let comma = if idx + 1 < entries.len() { "," } else { "" };
+        writeln!(
+            out,
+            "    {{\"id\": {}, \"before\": {}, \"after\": {after}, \"status\": {}, \"diagnostics\": {}}}{comma}",
+            entry.id + 1,
+            json_string(&text(entry.before)),
+            json_string(entry.status),
+            json_diagnostics(&entry.diagnostics),
+        )?;
+    }
+    writeln!(out, "  ],")?;
+
+    writeln!(out, "  \"diagnostics\": {},", json_diagnostics(&general))?;
+
+    if steps.is_empty() {
+        writeln!(out, "  \"steps\": []")?;
+    } else {
+        writeln!(out, "  \"steps\": [")?;
+        for (idx, step) in steps.iter().enumerate() {
+            let comma = if idx + 1 < steps.len() { "," } else { "" };
+            writeln!(out, "    {}{comma}", json_step(step))?;
+        }
+        writeln!(out, "  ]")?;
+    }
+
+    writeln!(out, "}}")
+}
+
+/// Returns `field` quoted for CSV, if necessary.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+fn write_csv(out: &mut impl Write, plan: &RenamePlan, diagnostics: &Diagnostics) -> io::Result<()> {
+    let (entries, _) = entries(plan, diagnostics);
+
+    writeln!(out, "id,before,after,status,diagnostics")?;
+    for entry in entries {
+        let diagnostics: Vec<_> = entry
+            .diagnostics
+            .iter()
+            .map(|(severity, message)| format!("{severity}: {message}"))
+            .collect();
+
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            entry.id + 1,
+            csv_field(&text(entry.before)),
+            csv_field(&entry.after.map(text).unwrap_or_default()),
+            entry.status,
+            csv_field(&diagnostics.join("; ")),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Returns `path` quoted for the shell, if necessary.
+fn shell_quote(path: &Path) -> Vec<u8> {
+    let bytes = path.as_os_str().as_bytes();
+    let is_safe = |byte: &u8| byte.is_ascii_alphanumeric() || b"_./+,:@%=-".contains(byte);
+    if !bytes.is_empty() && bytes.iter().all(is_safe) {
+        return bytes.to_vec();
+    }
+
+    let mut quoted = vec![b'\''];
+    for &byte in bytes {
+        match byte {
+            b'\'' => quoted.extend(b"'\\''"),
+            _ => quoted.push(byte),
+        }
+    }
+
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Writes a command with the given arguments followed by `--` and the paths.
+fn write_command(out: &mut impl Write, command: &str, paths: &[&Path]) -> io::Result<()> {
+    out.write_all(command.as_bytes())?;
+    out.write_all(b" --")?;
+    for path in paths {
+        out.write_all(b" ")?;
+        out.write_all(&shell_quote(path))?;
+    }
+
+    out.write_all(b"\n")
+}
+
+fn write_sh(out: &mut impl Write, steps: &[Step]) -> io::Result<()> {
+    writeln!(out, "#!/bin/sh")?;
+    // escaped, such that newlines in the path can't end the comment
+    let cwd = std::env::current_dir()?;
+    writeln!(out, "# planned by evaki in {}", escape::display(&cwd))?;
+    writeln!(out, "set -eu")?;
+    writeln!(out)?;
+
+    for step in steps {
+        match step {
+            Step::Rename { from, to } => write_command(out, "mv -n", &[from, to])?,
+            Step::Exchange { a, b } => {
+                let temp = plan::temp_name(a, &BTreeSet::from([a.clone(), b.clone()]), &Os);
+                write_command(out, "mv -n", &[a, &temp])?;
+                write_command(out, "mv -n", &[b, a])?;
+                write_command(out, "mv -n", &[&temp, b])?;
+            }
+            Step::Copy { from, to, mode } => match mode {
+                CopyMode::Copy => write_command(out, "cp -R -n", &[from, to])?,
+                CopyMode::Hardlink => write_command(out, "cp -R -l -n", &[from, to])?,
+                CopyMode::Reflink => write_command(out, "cp -R -n --reflink=auto", &[from, to])?,
+                CopyMode::Symlink => {
+                    let ancestor = to.parent().unwrap_or(Path::new(""));
+                    let target = copy::relative_to(from, ancestor)?;
+                    write_command(out, "ln -s", &[&target, to])?;
+                }
+            },
+            Step::Remove { path } | Step::Trash { path } => write_command(out, "rm -r", &[path])?,
+            Step::Restore { file, info, path } => {
+                write_command(out, "mv -n", &[file, path])?;
+                write_command(out, "rm", &[info])?;
+            }
+            Step::CreateDir { path } => write_command(out, "mkdir", &[path])?,
+            Step::RemoveDir { path } => write_command(out, "rmdir", &[path])?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn paths_are_shell_quoted() {
+        let quote = |path: &str| String::from_utf8(shell_quote(Path::new(path))).unwrap();
+
+        assert_eq!(quote("dir/a-b_c.txt"), "dir/a-b_c.txt");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote("$HOME\n*"), "'$HOME\n*'");
+
+        for path in ["a b", "it's", "'", "$(rm -rf ~)", "tab\there"] {
+            assert_eq!(shell_words::split(&quote(path)).unwrap(), [path]);
+        }
+
+        // bytes which are not valid UTF-8 are kept as they are
+        let path = Path::new(OsStr::from_bytes(b"\xFF name"));
+        assert_eq!(shell_quote(path), b"'\xFF name'");
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("a\"b\\c"), r#""a\"b\\c""#);
+        assert_eq!(json_string("a\nb\tc"), r#""a\nb\tc""#);
+        assert_eq!(json_string("bell\x07\u{7f}"), r#""bell\u0007\u007f""#);
+        assert_eq!(json_string("café"), "\"café\"");
+    }
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("a\r\nb"), "\"a\r\nb\"");
+    }
+}
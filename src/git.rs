@@ -0,0 +1,361 @@
+//! Keeping the git index in sync with renames, like `git mv` does.
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::escape;
+use crate::plan::Step;
+
+/// A git work tree which the current directory is in.
+#[derive(Debug, Clone)]
+pub struct Git {
+    /// The absolute path of the top level of the work tree.
+    top: PathBuf,
+
+    /// The absolute path of the current directory.
+    cwd: PathBuf,
+}
+
+/// An entry of the index, consisting of `mode sha stage` and its path relative to the top level.
+type Entry = (String, PathBuf);
+
+impl Git {
+    /// Returns the work tree the current directory is in, if any.
+    pub fn discover() -> io::Result<Option<Git>> {
+        let output = match Command::new("git")
+            .args(["rev-parse", "--show-toplevel"])
+            .stderr(Stdio::null())
+            .output()
+        {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let mut top = output.stdout;
+        if top.ends_with(b"\n") {
+            top.pop();
+        }
+
+        Ok(Some(Git {
+            top: PathBuf::from(OsString::from_vec(top)),
+            cwd: std::env::current_dir()?,
+        }))
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new("git");
+        command.arg("--literal-pathspecs").arg("-C").arg(&self.top);
+        command
+    }
+
+    /// Returns `path` relative to the top level of the work tree.
+    fn relative(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = std::path::absolute(self.cwd.join(path))?;
+        path.strip_prefix(&self.top)
+            .map(Path::to_path_buf)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is outside of the work tree", escape::display(&path)),
+                )
+            })
+    }
+
+    /// Returns the index entries of all tracked files at or below the given paths.
+    fn entries(&self, paths: &[PathBuf]) -> io::Result<Vec<Entry>> {
+        let mut entries = vec![];
+
+        // paths are passed as arguments, so they are chunked to stay below argument limits
+        for chunk in paths.chunks(1024) {
+            let output = self
+                .command()
+                .args(["ls-files", "--stage", "-z", "--full-name", "--"])
+                .args(chunk)
+                .stderr(Stdio::inherit())
+                .output()?;
+
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "git ls-files exited with: {}",
+                    output.status
+                )));
+            }
+
+            // only the info is text, paths may not be valid UTF-8
+            entries.extend(
+                output
+                    .stdout
+                    .split(|&byte| byte == b'\0')
+                    .filter_map(|entry| {
+                        let tab = entry.iter().position(|&byte| byte == b'\t')?;
+                        let info = String::from_utf8_lossy(&entry[..tab]).into_owned();
+                        let path = OsString::from_vec(entry[tab + 1..].to_vec());
+                        Some((info, PathBuf::from(path)))
+                    }),
+            );
+        }
+
+        Ok(entries)
+    }
+
+    /// Applies `mode sha stage\tpath` lines to the index.
+    fn update_index(&self, lines: &[(String, PathBuf)]) -> io::Result<()> {
+        let mut child = self
+            .command()
+            .args(["update-index", "-z", "--index-info"])
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        for (info, path) in lines {
+            stdin.write_all(format!("{info}\t").as_bytes())?;
+            stdin.write_all(path.as_os_str().as_bytes())?;
+            stdin.write_all(b"\0")?;
+        }
+        drop(stdin);
+
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "git update-index exited with: {status}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the lines removing the given entries from the index.
+    fn removals(entries: &[Entry]) -> impl Iterator<Item = (String, PathBuf)> + '_ {
+        entries.iter().map(|(info, path)| {
+            let (_, sha, stage) = split_info(info);
+            (format!("0 {} {stage}", "0".repeat(sha.len())), path.clone())
+        })
+    }
+
+    /// Returns the lines adding the given entries below `to` instead of `from`.
+    fn moved<'a>(
+        entries: &'a [Entry],
+        from: &'a Path,
+        to: &'a Path,
+    ) -> impl Iterator<Item = (String, PathBuf)> + 'a {
+        entries.iter().map(move |(info, path)| {
+            let rest = path.strip_prefix(from).unwrap_or(Path::new(""));
+            let path = match rest.as_os_str().is_empty() {
+                true => to.to_path_buf(),
+                false => to.join(rest),
+            };
+
+            (info.clone(), path)
+        })
+    }
+
+    /// Moves the index entries at or below `from` to `to`.
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (self.relative(from)?, self.relative(to)?);
+        let entries = self.entries(std::slice::from_ref(&from))?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let lines: Vec<_> =
+            Iterator::chain(Self::removals(&entries), Self::moved(&entries, &from, &to)).collect();
+
+        self.update_index(&lines)
+    }
+
+    /// Swaps the index entries at or below `a` with those at or below `b`.
+    pub fn exchange(&self, a: &Path, b: &Path) -> io::Result<()> {
+        let (a, b) = (self.relative(a)?, self.relative(b)?);
+        let entries_a = self.entries(std::slice::from_ref(&a))?;
+        let entries_b = self.entries(std::slice::from_ref(&b))?;
+        if entries_a.is_empty() && entries_b.is_empty() {
+            return Ok(());
+        }
+
+        let lines: Vec<_> = Self::removals(&entries_a)
+            .chain(Self::removals(&entries_b))
+            .chain(Self::moved(&entries_a, &a, &b))
+            .chain(Self::moved(&entries_b, &b, &a))
+            .collect();
+
+        self.update_index(&lines)
+    }
+
+    /// Returns for each step whether it moves tracked files.
+    pub fn tracked_steps(&self, steps: &[Step]) -> io::Result<Vec<bool>> {
+        let mut paths = BTreeSet::new();
+        for step in steps {
+            match step {
+                Step::Rename { from, .. } => {
+                    paths.insert(self.relative(from)?);
+                }
+                Step::Exchange { a, b } => {
+                    paths.insert(self.relative(a)?);
+                    paths.insert(self.relative(b)?);
+                }
+                _ => {}
+            }
+        }
+
+        if paths.is_empty() {
+            return Ok(vec![false; steps.len()]);
+        }
+
+        let paths: Vec<_> = paths.into_iter().collect();
+        let entries = self.entries(&paths)?;
+
+        // a path is tracked if any tracked file is at or below it
+        let mut tracked: BTreeSet<_> = paths
+            .into_iter()
+            .filter(|path| entries.iter().any(|(_, entry)| entry.starts_with(path)))
+            .collect();
+
+        // follow tracked paths through the steps
+        let mut tracked_steps = vec![];
+        for step in steps {
+            let is_tracked = match step {
+                Step::Rename { from, to } => {
+                    let is_tracked = tracked.remove(&self.relative(from)?);
+                    if is_tracked {
+                        tracked.insert(self.relative(to)?);
+                    }
+
+                    is_tracked
+                }
+                Step::Exchange { a, b } => {
+                    let (a, b) = (self.relative(a)?, self.relative(b)?);
+                    let (is_a, is_b) = (tracked.remove(&a), tracked.remove(&b));
+                    if is_a {
+                        tracked.insert(b);
+                    }
+                    if is_b {
+                        tracked.insert(a);
+                    }
+
+                    is_a || is_b
+                }
+                _ => false,
+            };
+
+            tracked_steps.push(is_tracked);
+        }
+
+        Ok(tracked_steps)
+    }
+}
+
+/// Splits `mode sha stage` into its parts.
+fn split_info(info: &str) -> (&str, &str, &str) {
+    let mut parts = info.splitn(3, ' ');
+    let mode = parts.next().unwrap_or_default();
+    let sha = parts.next().unwrap_or_default();
+    let stage = parts.next().unwrap_or_default();
+
+    (mode, sha, stage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA: &str = "e69de29bb2d1d6435b8b9c4e9d1a12b6b1c2b6a5";
+
+    fn entries() -> Vec<Entry> {
+        ["dir/a", "dir/sub/b"]
+            .into_iter()
+            .map(|path| (format!("100644 {SHA} 0"), PathBuf::from(path)))
+            .collect()
+    }
+
+    #[test]
+    fn index_info_lines() {
+        let removals: Vec<_> = Git::removals(&entries()).collect();
+        assert_eq!(
+            removals,
+            [
+                (format!("0 {} 0", "0".repeat(40)), PathBuf::from("dir/a")),
+                (
+                    format!("0 {} 0", "0".repeat(40)),
+                    PathBuf::from("dir/sub/b")
+                ),
+            ]
+        );
+
+        let moved: Vec<_> = Git::moved(&entries(), Path::new("dir"), Path::new("new")).collect();
+        assert_eq!(
+            moved,
+            [
+                (format!("100644 {SHA} 0"), PathBuf::from("new/a")),
+                (format!("100644 {SHA} 0"), PathBuf::from("new/sub/b")),
+            ]
+        );
+
+        let entries = &entries()[..1];
+        let moved: Vec<_> = Git::moved(entries, Path::new("dir/a"), Path::new("c")).collect();
+        assert_eq!(moved, [(format!("100644 {SHA} 0"), PathBuf::from("c"))]);
+    }
+
+    #[test]
+    fn infos_are_split() {
+        assert_eq!(split_info("100644 abc 0"), ("100644", "abc", "0"));
+        assert_eq!(split_info("100644"), ("100644", "", ""));
+    }
+
+    #[test]
+    fn renames_update_the_index() {
+        let dir = std::env::temp_dir().join(format!("evaki-git-rename-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("dir")).unwrap();
+        for path in ["dir/a", "b"] {
+            std::fs::write(dir.join(path), path).unwrap();
+        }
+
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "git {args:?}");
+            String::from_utf8(output.stdout).unwrap()
+        };
+        git(&["init", "-q"]);
+        git(&["add", "dir/a", "b"]);
+
+        let repo = Git {
+            top: dir.clone(),
+            cwd: dir.join("dir"),
+        };
+        std::fs::rename(dir.join("dir/a"), dir.join("dir/c")).unwrap();
+        repo.rename(Path::new("a"), Path::new("c")).unwrap();
+        assert_eq!(git(&["ls-files"]), "b\ndir/c\n");
+
+        let steps = [
+            Step::Rename {
+                from: "c".into(),
+                to: "d".into(),
+            },
+            Step::Rename {
+                from: "untracked".into(),
+                to: "e".into(),
+            },
+            Step::Rename {
+                from: "d".into(),
+                to: "f".into(),
+            },
+        ];
+        assert_eq!(repo.tracked_steps(&steps).unwrap(), [true, false, true]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,417 @@
+//! A persistent journal of applied batches, such that they can be undone later.
+//!
+//! Each batch is stored in its own file `$XDG_STATE_HOME/evaki/<id>.journal`, ids are increasing.
+//! A batch consists of tab separated lines, `time` and `cwd` lines are followed by `step` lines,
+//! each of which is followed by an `undo` line, unless the step can't be undone. Fields are
+//! escaped like paths in the edit buffer, but never quoted.
+
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::copy::CopyMode;
+use crate::plan::Step;
+use crate::vfs::Filesystem;
+use crate::{escape, sys};
+
+/// An applied batch of steps.
+#[derive(Debug)]
+pub struct Batch {
+    /// The local time at which the batch was applied.
+    pub time: String,
+
+    /// The working directory relative paths of the batch are resolved against.
+    pub cwd: PathBuf,
+
+    /// Whether renames of tracked files were staged in the git index.
+    pub git: bool,
+
+    /// The applied steps and their inverses, if they can be undone.
+    pub steps: Vec<(Step, Option<Step>)>,
+}
+
+impl Batch {
+    /// Creates a batch of the `applied` steps and their inverses, which were applied just now in
+    /// `cwd`.
+    pub fn new(cwd: PathBuf, git: bool, applied: &[(Step, Step)]) -> Self {
+        // permanently removed paths can't be restored once the transaction is committed
+        let steps = applied
+            .iter()
+            .map(|(step, inverse)| match step {
+                Step::Remove { .. } => (step.clone(), None),
+                Step::RemoveDir { path } => {
+                    (step.clone(), Some(Step::CreateDir { path: path.clone() }))
+                }
+                _ => (step.clone(), Some(inverse.clone())),
+            })
+            .collect();
+
+        Self {
+            time: sys::local_time(),
+            cwd,
+            git,
+            steps,
+        }
+    }
+
+    /// Returns the steps undoing this batch in the order they are applied, steps which can't be
+    /// undone are skipped.
+    pub fn undo_steps(&self) -> Vec<Step> {
+        self.steps
+            .iter()
+            .rev()
+            .filter_map(|(_, inverse)| inverse.clone())
+            .collect()
+    }
+
+    /// Returns the paths which must exist in `fs` if nothing was changed since this batch was
+    /// applied, but don't.
+    pub fn missing_paths(&self, fs: &impl Filesystem) -> Vec<&Path> {
+        self.expected_paths()
+            .into_iter()
+            .filter(|path| !fs.exists(path))
+            .collect()
+    }
+
+    /// Returns the paths which must exist if nothing was changed since this batch was applied.
+    pub fn expected_paths(&self) -> BTreeSet<&Path> {
+        let mut paths = BTreeSet::new();
+        for (step, inverse) in &self.steps {
+            match (step, inverse) {
+                (Step::Rename { from, to }, _) => {
+                    paths.remove(from.as_path());
+                    paths.insert(to.as_path());
+                }
+                (Step::Exchange { a, b }, _) => {
+                    paths.insert(a.as_path());
+                    paths.insert(b.as_path());
+                }
+                (Step::Copy { to, .. }, _) => {
+                    paths.insert(to.as_path());
+                }
+                (Step::Trash { path }, Some(Step::Restore { file, .. })) => {
+                    paths.remove(path.as_path());
+                    paths.insert(file.as_path());
+                }
+                (Step::Remove { path } | Step::Trash { path } | Step::RemoveDir { path }, _) => {
+                    paths.remove(path.as_path());
+                }
+                (Step::Restore { file, path, .. }, _) => {
+                    paths.remove(file.as_path());
+                    paths.insert(path.as_path());
+                }
+                (Step::CreateDir { path }, _) => {
+                    paths.insert(path.as_path());
+                }
+            }
+        }
+
+        paths
+    }
+}
+
+/// Returns the directory the journal and other state is stored in.
+pub fn dir() -> io::Result<PathBuf> {
+    if let Some(state) = std::env::var_os("XDG_STATE_HOME").filter(|state| !state.is_empty()) {
+        return Ok(PathBuf::from(state).join("evaki"));
+    }
+
+    match std::env::var_os("HOME").filter(|home| !home.is_empty()) {
+        Some(home) => Ok(PathBuf::from(home).join(".local/state/evaki")),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "neither XDG_STATE_HOME nor HOME are set",
+        )),
+    }
+}
+
+fn path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id}.journal"))
+}
+
+/// Returns the ids of all recorded batches in ascending order.
+pub fn ids() -> io::Result<Vec<u64>> {
+    let entries = match fs::read_dir(dir()?) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    let mut ids = vec![];
+    for entry in entries {
+        let name = entry?.file_name();
+        if let Some(id) = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".journal"))
+            .and_then(|id| id.parse().ok())
+        {
+            ids.push(id);
+        }
+    }
+
+    ids.sort();
+    Ok(ids)
+}
+
+fn encode_step(step: &Step) -> String {
+    let fields: Vec<&OsStr> = match step {
+        Step::Rename { from, to } => vec!["rename".as_ref(), from.as_ref(), to.as_ref()],
+        Step::Exchange { a, b } => vec!["exchange".as_ref(), a.as_ref(), b.as_ref()],
+        Step::Copy { from, to, mode } => {
+            let kind = match mode {
+                CopyMode::Copy => "copy",
+                CopyMode::Hardlink => "hardlink",
+                CopyMode::Symlink => "symlink",
+                CopyMode::Reflink => "reflink",
+            };
+
+            vec![kind.as_ref(), from.as_ref(), to.as_ref()]
+        }
+        Step::Remove { path } => vec!["remove".as_ref(), path.as_ref()],
+        Step::Trash { path } => vec!["trash".as_ref(), path.as_ref()],
+        Step::Restore { file, info, path } => vec![
+            "restore".as_ref(),
+            file.as_ref(),
+            info.as_ref(),
+            path.as_ref(),
+        ],
+        Step::CreateDir { path } => vec!["mkdir".as_ref(), path.as_ref()],
+        Step::RemoveDir { path } => vec!["rmdir".as_ref(), path.as_ref()],
+    };
+
+    fields
+        .into_iter()
+        .map(escape::escape)
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+fn decode_step(fields: &[OsString]) -> Option<Step> {
+    let [kind, rest @ ..] = fields else {
+        return None;
+    };
+
+    let step = match (kind.to_str()?, rest) {
+        ("rename", [from, to]) => Step::Rename {
+            from: from.into(),
+            to: to.into(),
+        },
+        ("exchange", [a, b]) => Step::Exchange {
+            a: a.into(),
+            b: b.into(),
+        },
+        (kind @ ("copy" | "hardlink" | "symlink" | "reflink"), [from, to]) => Step::Copy {
+            from: from.into(),
+            to: to.into(),
+            mode: match kind {
+                "copy" => CopyMode::Copy,
+                "hardlink" => CopyMode::Hardlink,
+                "symlink" => CopyMode::Symlink,
+                _ => CopyMode::Reflink,
+            },
+        },
+        ("remove", [path]) => Step::Remove { path: path.into() },
+        ("trash", [path]) => Step::Trash { path: path.into() },
+        ("restore", [file, info, path]) => Step::Restore {
+            file: file.into(),
+            info: info.into(),
+            path: path.into(),
+        },
+        ("mkdir", [path]) => Step::CreateDir { path: path.into() },
+        ("rmdir", [path]) => Step::RemoveDir { path: path.into() },
+        _ => return None,
+    };
+
+    Some(step)
+}
+
+/// Records a batch in the journal and returns its id.
+pub fn record(batch: &Batch) -> io::Result<u64> {
+    let mut content = String::new();
+    content.push_str(&format!("time\t{}\n", escape::escape(batch.time.as_ref())));
+    content.push_str(&format!("cwd\t{}\n", escape::escape(batch.cwd.as_ref())));
+    if batch.git {
+        content.push_str("git\n");
+    }
+    for (step, inverse) in &batch.steps {
+        content.push_str(&format!("step\t{}\n", encode_step(step)));
+        if let Some(inverse) = inverse {
+            content.push_str(&format!("undo\t{}\n", encode_step(inverse)));
+        }
+    }
+
+    let dir = dir()?;
+    fs::create_dir_all(&dir)?;
+
+    let mut id = ids()?.last().map_or(1, |last| last + 1);
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path(&dir, id))
+        {
+            Ok(mut file) => {
+                file.write_all(content.as_bytes())?;
+                file.sync_all()?;
+                return Ok(id);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => id += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Loads the batch with the given id from the journal.
+pub fn load(id: u64) -> io::Result<Batch> {
+    let content = fs::read_to_string(path(&dir()?, id))?;
+    let invalid = |line: usize| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid journal entry {id} on line {line}"),
+        )
+    };
+
+    let mut batch = Batch {
+        time: String::new(),
+        cwd: PathBuf::new(),
+        git: false,
+        steps: vec![],
+    };
+
+    for (idx, line) in content.lines().enumerate() {
+        let fields: Vec<_> = line
+            .split('\t')
+            .map(|field| escape::unescape(field.as_bytes()))
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid(idx + 1))?;
+
+        match fields.as_slice() {
+            [kind, time] if kind == "time" => {
+                batch.time = time.to_string_lossy().into_owned();
+            }
+            [kind, cwd] if kind == "cwd" => batch.cwd = PathBuf::from(cwd),
+            [kind] if kind == "git" => batch.git = true,
+            [kind, step @ ..] if kind == "step" => {
+                let step = decode_step(step).ok_or_else(|| invalid(idx + 1))?;
+                batch.steps.push((step, None));
+            }
+            [kind, inverse @ ..] if kind == "undo" => {
+                let inverse = decode_step(inverse).ok_or_else(|| invalid(idx + 1))?;
+                let (_, slot) = batch.steps.last_mut().ok_or_else(|| invalid(idx + 1))?;
+                *slot = Some(inverse);
+            }
+            _ => return Err(invalid(idx + 1)),
+        }
+    }
+
+    Ok(batch)
+}
+
+/// Removes the batch with the given id from the journal.
+pub fn remove(id: u64) -> io::Result<()> {
+    fs::remove_file(path(&dir()?, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+    use crate::apply::Transaction;
+    use crate::vfs::{Memory, Node};
+
+    #[test]
+    fn steps_round_trip() {
+        let odd = PathBuf::from(OsStr::from_bytes(b"odd\tname\n\xFF"));
+        let steps = [
+            Step::Rename {
+                from: "a".into(),
+                to: odd.clone(),
+            },
+            Step::Exchange {
+                a: "a".into(),
+                b: "b".into(),
+            },
+            Step::Copy {
+                from: "a".into(),
+                to: "b".into(),
+                mode: CopyMode::Symlink,
+            },
+            Step::Remove { path: odd.clone() },
+            Step::Trash { path: "a".into() },
+            Step::Restore {
+                file: "file".into(),
+                info: "info".into(),
+                path: "a".into(),
+            },
+            Step::CreateDir { path: "d".into() },
+            Step::RemoveDir { path: "d".into() },
+        ];
+
+        for step in steps {
+            let fields: Option<Vec<_>> = encode_step(&step)
+                .split('\t')
+                .map(|field| escape::unescape(field.as_bytes()))
+                .collect();
+            assert_eq!(decode_step(&fields.unwrap()), Some(step));
+        }
+
+        assert_eq!(decode_step(&["rename".into(), "a".into()]), None);
+        assert_eq!(decode_step(&["move".into(), "a".into(), "b".into()]), None);
+    }
+
+    #[test]
+    fn batches_are_undone() {
+        let file = |contents: &str| Node::File(contents.as_bytes().to_vec());
+        let mut fs = Memory::new();
+        fs.insert("a", file("a"));
+        fs.insert("b", file("b"));
+        fs.insert("c", file("c"));
+        fs.insert("d", Node::Dir);
+
+        let steps = [
+            Step::Rename {
+                from: "a".into(),
+                to: "x".into(),
+            },
+            Step::Copy {
+                from: "b".into(),
+                to: "y".into(),
+                mode: CopyMode::Copy,
+            },
+            Step::Remove { path: "c".into() },
+            Step::RemoveDir { path: "d".into() },
+        ];
+
+        let transaction = Transaction::run(&mut fs, None, &steps).unwrap();
+        let batch = Batch::new("/".into(), false, transaction.applied());
+        assert!(transaction.commit().is_empty());
+
+        assert_eq!(
+            batch.expected_paths(),
+            BTreeSet::from([Path::new("x"), Path::new("y")])
+        );
+        assert_eq!(batch.missing_paths(&fs), [] as [&Path; 0]);
+        assert_eq!(
+            batch.undo_steps(),
+            [
+                Step::CreateDir { path: "d".into() },
+                Step::Remove { path: "y".into() },
+                Step::Rename {
+                    from: "x".into(),
+                    to: "a".into(),
+                },
+            ]
+        );
+
+        let transaction = Transaction::run(&mut fs, None, &batch.undo_steps()).unwrap();
+        assert!(transaction.commit().is_empty());
+
+        // removed paths can't be restored
+        let nodes: Vec<_> = fs.nodes().map(|(path, _)| path.to_path_buf()).collect();
+        assert_eq!(nodes, [Path::new("a"), Path::new("b"), Path::new("d")]);
+        assert_eq!(fs.get("a"), Some(&file("a")));
+    }
+}
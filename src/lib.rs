@@ -0,0 +1,23 @@
+//! Batch renaming of paths, such that chains and cycles of renames are applied safely.
+//!
+//! A [`RenamePlan`] is created from the paths before and after renaming them. It is validated into
+//! typed [`Diagnostics`](validate::Diagnostics) and applied through a
+//! [`Filesystem`](vfs::Filesystem), either the real one or one kept in memory.
+//!
+//! The paths after are edited as text in an edit [`buffer`], applied batches are recorded in a
+//! [`journal`], such that they can be undone.
+
+pub mod apply;
+pub mod buffer;
+pub mod copy;
+pub mod escape;
+pub mod git;
+pub mod journal;
+pub mod plan;
+pub mod rename;
+pub mod sys;
+pub mod trash;
+pub mod validate;
+pub mod vfs;
+
+pub use rename::RenamePlan;
@@ -1,178 +1,767 @@
-use std::collections::BTreeMap;
 use std::error::Error;
-use std::io::{BufRead, Write};
-use std::process::{Command, ExitCode, Stdio};
-use std::{collections::BTreeSet, path::PathBuf};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::process::ExitCode;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
 
 use clap::Parser;
+use evaki::copy::CopyMode;
+use evaki::git::Git;
+use evaki::vfs::Os;
+use evaki::{apply, escape, journal, plan, rename, validate, RenamePlan};
+use globset::Glob;
+use regex::bytes::Regex;
+use session::Session;
+
+mod edit;
+mod format;
+mod pairs;
+mod report;
+mod saved;
+mod session;
+mod sub;
+mod walk;
 
 #[derive(Debug, clap::Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    action: Option<Action>,
+
     /// Don't rename any files, show what would be renamed
     #[arg(long, short = 'n')]
     dry_run: bool,
 
-    /// The editor to use
-    #[arg(long, short, env = "EDITOR")]
-    editor: PathBuf,
+    /// Allow moving files between directories, missing ancestors are created
+    #[arg(long = "move", short)]
+    move_files: bool,
+
+    /// Copy files to their new paths instead of renaming them
+    #[arg(long, group = "mode")]
+    copy: bool,
+
+    /// Hard link files to their new paths instead of renaming them
+    #[arg(long, group = "mode")]
+    hardlink: bool,
+
+    /// Symlink files to their new paths instead of renaming them, links are relative
+    #[arg(long, group = "mode")]
+    symlink: bool,
+
+    /// Reflink files to their new paths instead of renaming them, falls back to copying
+    #[arg(long, group = "mode")]
+    reflink: bool,
+
+    /// Replace existing files which are not part of the renamed files
+    #[arg(long, short, conflicts_with = "backup")]
+    force: bool,
+
+    /// Move existing files which are not part of the renamed files aside by appending a suffix
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "~"
+    )]
+    backup: Option<String>,
+
+    /// Stage renames of tracked files in the git index, like `git mv` does
+    #[arg(long)]
+    git: bool,
+
+    /// Delete files whose lines were removed from the buffer, they are moved to the trash
+    #[arg(long, short, conflicts_with = "mode")]
+    delete: bool,
+
+    /// Permanently delete removed files instead of moving them to the trash
+    #[arg(long, requires = "delete")]
+    no_trash: bool,
+
+    /// Remove directories which are left empty after moving files out of them
+    #[arg(long, requires = "move_files")]
+    prune: bool,
+
+    /// Refuse to change the extensions of files instead of only warning about it
+    #[arg(long)]
+    stem_only: bool,
+
+    /// Hide the extensions of files while editing, such that they are kept
+    #[arg(long, conflicts_with = "sub")]
+    hide_extensions: bool,
+
+    /// The editor command to use, defaults to EVAKI_EDITOR, VISUAL, EDITOR or vi in this order
+    #[arg(long, short, value_name = "CMD")]
+    editor: Option<String>,
+
+    /// Rename files with a sed-style substitution `s/REGEX/REPLACEMENT/FLAGS` instead of an
+    /// editor, `&` and `\1` to `\9` insert the match and its groups, flags are `g` and `i`. Regexes
+    /// match bytes, `.` matches any byte and classes like `\w` only match ASCII
+    #[arg(long, value_name = "EXPR", value_parser = sub::Substitution::parse)]
+    sub: Vec<sub::Substitution>,
+
+    /// The part of paths substitutions are applied to
+    #[arg(
+        long,
+        value_name = "SCOPE",
+        value_enum,
+        default_value_t,
+        requires = "sub"
+    )]
+    sub_scope: sub::Scope,
+
+    /// Rename files by piping their paths through a shell command instead of an editor, it must
+    /// output one line per path in the same order
+    #[arg(long, value_name = "CMD", conflicts_with = "sub")]
+    filter: Option<String>,
+
+    /// Rename files to the paths paired with them in a file instead of an editor, each line holds
+    /// the old and new path separated by a tab or a comma, old paths may only repeat when copying,
+    /// pass `-` to read from stdin
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["files", "files_from", "recursive", "filter", "sub", "null", "resume"]
+    )]
+    pairs: Option<PathBuf>,
+
+    /// Read files separated by NUL instead of newlines from stdin or --files-from
+    #[arg(long, short = '0')]
+    null: bool,
+
+    /// Read the files to rename from a file, pass `-` to read from stdin
+    #[arg(long, value_name = "PATH")]
+    files_from: Option<PathBuf>,
+
+    /// Replace directories with their contents recursively
+    #[arg(long, short)]
+    recursive: bool,
+
+    /// Only expand directories into files (f) or directories (d)
+    #[arg(long = "type", value_name = "TYPE", requires = "recursive")]
+    entry_type: Option<walk::EntryType>,
+
+    /// Only expand directories into entries matching a glob, globs without `/` match names
+    #[arg(long, value_name = "GLOB", value_parser = Glob::new, requires = "recursive")]
+    include: Vec<Glob>,
+
+    /// Don't expand directories into entries matching a glob, matching directories are skipped
+    #[arg(long, value_name = "GLOB", value_parser = Glob::new, requires = "recursive")]
+    exclude: Vec<Glob>,
+
+    /// Only expand directories into entries whose relative path matches a regex
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new, requires = "recursive")]
+    include_regex: Vec<Regex>,
+
+    /// Don't expand directories into entries whose relative path matches a regex
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new, requires = "recursive")]
+    exclude_regex: Vec<Regex>,
+
+    /// Expand directories into hidden entries too
+    #[arg(long, requires = "recursive")]
+    hidden: bool,
+
+    /// Don't descend further than this into directories, direct contents have a depth of 1
+    #[arg(long, value_name = "DEPTH", requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// Don't expand directories into entries ignored by .gitignore and .ignore files
+    #[arg(long, requires = "recursive")]
+    gitignore: bool,
+
+    /// Print the final paths to stdout, each terminated by NUL
+    #[arg(long)]
+    print0: bool,
+
+    /// Print the plan to stdout instead of applying it, paths in json and csv are escaped like in
+    /// the edit buffer
+    #[arg(long, value_name = "FORMAT", value_enum, conflicts_with = "print0")]
+    format: Option<format::Format>,
+
+    /// Save the validated plan to a file instead of applying it, apply it later with `evaki apply`
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["dry_run", "format", "print0"]
+    )]
+    save_plan: Option<PathBuf>,
+
+    /// Continue with an edit buffer which was kept after a failure or a dry run
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["files", "files_from", "recursive", "filter", "sub"]
+    )]
+    resume: Option<PathBuf>,
 
     /// The files to rename, pass `-` to read form stdin
-    #[arg(required = true, num_args(1..))]
-    files: Vec<String>,
+    #[arg(required_unless_present_any = ["files_from", "pairs", "resume"], num_args(1..))]
+    files: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Action {
+    /// Revert a batch of applied changes, defaults to the last batch
+    Undo {
+        /// The id of the batch to revert
+        id: Option<u64>,
+
+        /// List the recorded batches instead
+        #[arg(long, short, conflicts_with = "id")]
+        list: bool,
+
+        /// Don't revert any changes, show what would be reverted
+        #[arg(long, short = 'n')]
+        dry_run: bool,
+    },
+
+    /// Apply a plan saved with --save-plan, unless its files changed since
+    Apply {
+        /// The saved plan
+        path: PathBuf,
+
+        /// Don't apply the plan, show what would be changed
+        #[arg(long, short = 'n')]
+        dry_run: bool,
+    },
+}
+
+impl Args {
+    /// Returns how files are copied if they are not renamed.
+    fn copy_mode(&self) -> Option<CopyMode> {
+        if self.copy {
+            Some(CopyMode::Copy)
+        } else if self.hardlink {
+            Some(CopyMode::Hardlink)
+        } else if self.symlink {
+            Some(CopyMode::Symlink)
+        } else if self.reflink {
+            Some(CopyMode::Reflink)
+        } else {
+            None
+        }
+    }
+
+    /// Returns how the paths are changed.
+    fn plan_options(&self) -> rename::Options {
+        rename::Options {
+            copy_mode: self.copy_mode(),
+            delete: self.delete,
+            trash: !self.no_trash,
+            move_files: self.move_files,
+            stem_only: self.stem_only,
+            force: self.force,
+            backup_suffix: self.backup.clone(),
+            prune: self.prune,
+        }
+    }
+
+    /// Returns the flags which decide how the edit buffer is read.
+    fn buffer_flags(&self) -> session::Flags {
+        session::Flags {
+            copy_mode: self.copy_mode(),
+            delete: self.delete,
+            no_trash: self.no_trash,
+            move_files: self.move_files,
+            hide_extensions: self.hide_extensions,
+        }
+    }
+
+    /// Sets the flags which decide how the edit buffer is read.
+    fn set_buffer_flags(&mut self, flags: session::Flags) {
+        self.copy = flags.copy_mode == Some(CopyMode::Copy);
+        self.hardlink = flags.copy_mode == Some(CopyMode::Hardlink);
+        self.symlink = flags.copy_mode == Some(CopyMode::Symlink);
+        self.reflink = flags.copy_mode == Some(CopyMode::Reflink);
+        self.delete = flags.delete;
+        self.no_trash = flags.no_trash;
+        self.move_files = flags.move_files;
+        self.hide_extensions = flags.hide_extensions;
+    }
+
+    /// Returns how directories are expanded.
+    fn walk_options(&self) -> walk::Options {
+        walk::Options {
+            entry_type: self.entry_type,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            include_regex: self.include_regex.clone(),
+            exclude_regex: self.exclude_regex.clone(),
+            hidden: self.hidden,
+            max_depth: self.max_depth,
+            ignore_files: self.gitignore,
+        }
+    }
 }
 
 fn main() -> ExitCode {
-    main_impl().unwrap()
+    match main_impl() {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
 }
 
-fn get_ancestor(path: &str) -> Option<&str> {
-    path.strip_suffix('/')
-        .unwrap_or(path)
-        .rsplit_once('/')
-        .map(|(stem, _)| stem)
+/// Reads paths separated by newlines or NUL, empty paths are skipped.
+fn read_paths(reader: impl BufRead, null: bool) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for path in reader.split(if null { b'\0' } else { b'\n' }) {
+        let mut path = path?;
+        if !null && path.ends_with(b"\r") {
+            path.pop();
+        }
+
+        if path.is_empty() {
+            continue;
+        }
+
+        paths.push(PathBuf::from(OsString::from_vec(path)));
+    }
+
+    Ok(paths)
 }
 
 fn main_impl() -> Result<ExitCode, Box<dyn Error>> {
     let mut args = Args::parse();
 
-    if args.files.len() == 1 && args.files.first().is_some_and(|f| f == "-") {
-        args.files.clear();
+    match args.action {
+        Some(Action::Undo { id, list, dry_run }) => return undo_impl(id, list, dry_run),
+        Some(Action::Apply { path, dry_run }) => return apply_impl(&path, dry_run),
+        None => {}
+    }
+
+    if let Some(path) = &mut args.save_plan {
+        *path = std::path::absolute(&path)?;
+    }
+
+    if let Some(path) = &args.pairs {
+        let pairs = match pairs::load(path, std::io::stdin().lock()) {
+            Ok(pairs) if pairs.is_empty() => {
+                eprintln!("no pairs provided in {}", path.display());
+                return Ok(ExitCode::FAILURE);
+            }
+            Ok(pairs) => pairs,
+            Err(err) => {
+                eprintln!("failed to read pairs from {}: {err}", path.display());
+                return Ok(ExitCode::FAILURE);
+            }
+        };
+
+        // unlike listed files, old paths of exported pairs may be outdated
+        let missing: BTreeSet<_> = pairs
+            .iter()
+            .map(|(old, _)| old)
+            .filter(|old| std::fs::symlink_metadata(old).is_err())
+            .collect();
+
+        if !missing.is_empty() {
+            eprintln!("missing paths:");
+            for path in missing {
+                eprintln!("{}", escape::display(path));
+            }
+
+            return Ok(ExitCode::FAILURE);
+        }
+
+        // repeated old paths are likely mistakes, they are only duplicated when asked to
+        let mut seen = BTreeSet::new();
+        let repeated: BTreeSet<_> = pairs
+            .iter()
+            .map(|(old, _)| old)
+            .filter(|old| !seen.insert(*old))
+            .collect();
+
+        if !repeated.is_empty() && args.copy_mode().is_none() {
+            eprintln!("duplicate old paths, pass --copy or another mode to duplicate them:");
+            for path in repeated {
+                eprintln!("{}", escape::display(path));
+            }
+
+            return Ok(ExitCode::FAILURE);
+        }
+
+        return rename(&args, RenamePlan::from_pairs(pairs, args.plan_options()));
+    }
 
-        let stdin = std::io::stdin().lock();
-        for line in stdin.lines() {
-            let line = line?;
-            args.files.push(line);
+    // resumed buffers are relative to the directory they were created in
+    let mut resumed = None;
+    if let Some(path) = &args.resume {
+        let (session, cwd, files) = Session::load(&std::path::absolute(path)?)?;
+
+        // the buffer is read with the flags it was created with
+        let flags = args.buffer_flags();
+        if flags != session::Flags::default() && flags != session.flags {
+            match session.flags.args().as_slice() {
+                [] => eprintln!(
+                    "the edit buffer was created without `{}`",
+                    flags.args().join(" ")
+                ),
+                args => eprintln!(
+                    "the edit buffer was created with `{}`, pass them or none of them",
+                    args.join(" ")
+                ),
+            }
+
+            return Ok(ExitCode::FAILURE);
         }
 
+        args.set_buffer_flags(session.flags);
+        std::env::set_current_dir(cwd)?;
+        args.files = files;
+        resumed = Some(session);
+    }
+
+    // the editor can't use stdin once the files were read from it
+    let mut stdin_read = false;
+
+    if args.files.len() == 1 && args.files.first().is_some_and(|f| f.as_os_str() == "-") {
+        args.files = read_paths(std::io::stdin().lock(), args.null)?;
+        stdin_read = true;
+
         if args.files.is_empty() {
             eprintln!("no files provided on stdin");
             return Ok(ExitCode::FAILURE);
         }
     }
 
+    if let Some(path) = &args.files_from {
+        let files = if path.as_os_str() == "-" {
+            stdin_read = true;
+            read_paths(std::io::stdin().lock(), args.null)?
+        } else {
+            read_paths(BufReader::new(File::open(path)?), args.null)?
+        };
+
+        if files.is_empty() {
+            eprintln!("no files provided in {}", path.display());
+            return Ok(ExitCode::FAILURE);
+        }
+
+        args.files.extend(files);
+    }
+
+    if args.recursive {
+        args.files = walk::expand(&args.files, &args.walk_options())?;
+
+        if args.files.is_empty() {
+            eprintln!("no files found");
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+
     // order and deduplicate
     let before: BTreeSet<_> = args.files.iter().cloned().collect();
     let before: Vec<_> = before.into_iter().collect();
 
-    let mut buffer = vec![];
+    // hidden extensions are appended again after editing
+    let (shown, extensions): (Vec<PathBuf>, Vec<&OsStr>) = before
+        .iter()
+        .map(|path| match args.hide_extensions && !path.is_dir() {
+            true => {
+                let (stem, extension) = plan::split_extension(path);
+                (PathBuf::from(stem), extension)
+            }
+            false => (path.clone(), OsStr::new("")),
+        })
+        .unzip();
+
+    let mut session = None;
+    let after = if let Some(command) = &args.filter {
+        match edit::filter(command, &shown)? {
+            Some(after) => edit::append_extensions(after, &extensions),
+            None => return Ok(ExitCode::FAILURE),
+        }
+    } else if args.sub.is_empty() {
+        match edit::edit(&args, &before, &shown, &extensions, resumed, stdin_read)? {
+            Some((after, edited)) => {
+                session = Some(edited);
+                after
+            }
+            None => return Ok(ExitCode::FAILURE),
+        }
+    } else {
+        let mut after = vec![];
+        let mut emptied = vec![];
+        for path in &before {
+            match sub::substitute(path, &args.sub, args.sub_scope) {
+                Some(path) => after.push(vec![path]),
+                None => emptied.push(path),
+            }
+        }
 
-    // write header and paths
-    writeln!(buffer, "// empty lines and coments are ignored")?;
-    writeln!(buffer, "// do not remove or reorder any lines")?;
-    writeln!(buffer, "// do not edit anything other than file stems")?;
-    writeln!(buffer)?;
-    for file in &before {
-        buffer.write_all(file.as_bytes())?;
-        writeln!(buffer)?;
-    }
+        if !emptied.is_empty() {
+            eprintln!("substitutions leaving empty names:");
+            for path in emptied {
+                eprintln!("{}", escape::display(path));
+            }
 
-    // write temp file and open it
-    let file = temp_file::with_contents(&buffer);
+            return Ok(ExitCode::FAILURE);
+        }
 
-    let output = Command::new(args.editor)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .arg(file.path())
-        .output()?;
+        after
+    };
 
-    if !output.status.success() {
-        eprintln!("editor exited with: {}", output.status);
-        return Ok(ExitCode::FAILURE);
+    // the edit buffer is only removed once it was applied
+    let res = rename(&args, RenamePlan::new(before, after, args.plan_options()));
+    if let Some(session) = session {
+        match res {
+            Ok(ExitCode::SUCCESS) if !args.dry_run && args.format.is_none() => session.remove()?,
+            _ => edit::keep(&session),
+        }
     }
 
-    // read temp file
-    let mut after = vec![];
-    let buffer = std::fs::read(file.path())?;
-    for line in buffer.lines() {
-        let line = line?;
+    res
+}
 
-        if line.is_empty() || line.starts_with("//") {
-            continue;
+/// Applies, prints or saves `plan` depending on `args`.
+fn rename(args: &Args, plan: RenamePlan) -> Result<ExitCode, Box<dyn Error>> {
+    let (before, after) = (plan.before(), plan.after());
+    let diagnostics = plan.validate(&Os);
+
+    if let Some(format) = args.format {
+        // scripts can't be written for invalid plans, csv has no room for general problems
+        let valid = diagnostics.is_valid();
+        if !valid && format != format::Format::Json {
+            report::problems(&diagnostics.problems, before, after);
+            if format == format::Format::Sh {
+                return Ok(ExitCode::FAILURE);
+            }
         }
 
-        after.push(line);
+        if format == format::Format::Sh {
+            report::warnings(&diagnostics.warnings, before, after);
+        }
+
+        let steps = match valid {
+            true => plan.steps(&Os)?,
+            false => vec![],
+        };
+
+        let mut stdout = io::stdout().lock();
+        format::write(format, &mut stdout, &plan, &diagnostics, &steps)?;
+        stdout.flush()?;
+
+        return match valid {
+            true => Ok(ExitCode::SUCCESS),
+            false => Ok(ExitCode::FAILURE),
+        };
     }
 
-    drop(file);
+    if let Some(path) = &args.save_plan {
+        if !diagnostics.is_valid() {
+            report::problems(&diagnostics.problems, before, after);
+            return Ok(ExitCode::FAILURE);
+        }
+
+        report::warnings(&diagnostics.warnings, before, after);
 
-    if before.len() != after.len() {
+        // show what the plan would change
+        let steps = plan.steps(&Os)?;
+        report::steps(&steps, None)?;
+
+        saved::save(path, &plan, args.git)?;
         eprintln!(
-            "had {} path(s) before, but {} path(s) after",
-            before.len(),
-            after.len(),
+            "the plan was saved, apply it with: evaki apply {}",
+            escape::display(path)
         );
 
-        return Ok(ExitCode::FAILURE);
+        return Ok(ExitCode::SUCCESS);
     }
 
-    // rename files in reverse order
-    let mut failure = false;
+    execute(&plan, diagnostics, args.git, args.dry_run, args.print0)
+}
 
-    let mut renamed_ancestors = BTreeSet::new();
-    let mut reverse_map: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+/// Applies a validated `plan` and records it in the journal, renames of tracked files are staged
+/// if `git` is set.
+fn execute(
+    plan: &RenamePlan,
+    diagnostics: validate::Diagnostics,
+    git: bool,
+    dry_run: bool,
+    print0: bool,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let (before, after) = (plan.before(), plan.after());
 
-    for (before, after) in Iterator::zip(before.iter(), after.iter()) {
-        let befores = reverse_map.entry(after).or_default();
-        befores.push(before);
+    if !diagnostics.is_valid() {
+        report::problems(&diagnostics.problems, before, after);
+        return Ok(ExitCode::FAILURE);
+    }
 
-        if befores.len() > 1 {
-            failure = true;
-        }
+    report::warnings(&diagnostics.warnings, before, after);
+
+    // the steps are only planned once, such that those shown are those applied
+    let steps = plan.steps(&Os)?;
 
-        if let Some((before_stem, after_stem)) =
-            Option::zip(get_ancestor(before), get_ancestor(after))
-        {
-            if before_stem != after_stem {
-                failure = true;
-                renamed_ancestors.insert((before_stem, after_stem));
+    let git_index = match git {
+        true => match Git::discover()? {
+            Some(git) => Some(git),
+            None => {
+                eprintln!("--git was passed, but the current directory is not in a git work tree");
+                return Ok(ExitCode::FAILURE);
             }
+        },
+        false => None,
+    };
+
+    report::steps(&steps, git_index.as_ref())?;
+
+    let transaction = match dry_run {
+        true => None,
+        false => match apply::Transaction::run(Os, git_index, &steps) {
+            Ok(transaction) => Some(transaction),
+            Err(err) => {
+                report::rolled_back(err, &steps)?;
+                return Ok(ExitCode::FAILURE);
+            }
+        },
+    };
+
+    if print0 {
+        let mut stdout = std::io::stdout().lock();
+        for path in after.iter().flatten() {
+            stdout.write_all(path.as_os_str().as_bytes())?;
+            stdout.write_all(b"\0")?;
         }
+        stdout.flush()?;
     }
 
-    // TODO: doesn't account for cross renames
-    if reverse_map.values().any(|befores| befores.len() > 1) {
-        eprintln!("duplicate renames:");
-        for (after, befores) in reverse_map
-            .into_iter()
-            .filter(|(_, befores)| befores.len() > 1)
-        {
-            eprintln!("-> {after}");
-            for before in befores {
-                eprintln!("<- {before}");
-            }
-            eprintln!();
+    let Some(transaction) = transaction.filter(|transaction| !transaction.is_empty()) else {
+        return Ok(ExitCode::SUCCESS);
+    };
+
+    let batch = journal::Batch::new(std::env::current_dir()?, git, transaction.applied());
+
+    let failure = commit(transaction);
+
+    if let Err(err) = journal::record(&batch) {
+        eprintln!("failed to record changes in the journal: {err}");
+    }
+
+    if failure {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Commits a transaction, returns whether any removal failed.
+fn commit(transaction: apply::Transaction) -> bool {
+    let mut failure = false;
+    for (path, err) in transaction.commit() {
+        eprintln!("failed to remove {}: {err}", escape::display(&path));
+        failure = true;
+    }
+
+    failure
+}
+
+fn apply_impl(path: &Path, dry_run: bool) -> Result<ExitCode, Box<dyn Error>> {
+    let saved = match saved::load(&std::path::absolute(path)?) {
+        Ok(saved) => saved,
+        Err(err) => {
+            eprintln!("failed to load the plan {}: {err}", escape::display(path));
+            return Ok(ExitCode::FAILURE);
         }
+    };
+
+    std::env::set_current_dir(&saved.cwd)?;
+
+    // make sure nothing changed since the plan was saved
+    let changed = saved.changed();
+    if !changed.is_empty() {
+        eprintln!("paths changed since the plan was saved, refusing to apply it:");
+        for (reason, path) in changed {
+            eprintln!("{reason} {}", escape::display(path));
+        }
+
+        return Ok(ExitCode::FAILURE);
     }
 
-    if !renamed_ancestors.is_empty() {
-        let pad = renamed_ancestors
-            .iter()
-            .map(|(b, _)| b.len())
-            .max()
-            .unwrap();
+    let diagnostics = saved.plan.validate(&Os);
+    execute(&saved.plan, diagnostics, saved.git, dry_run, false)
+}
 
-        eprintln!("inline renamed ancestors:");
-        for (before, after) in renamed_ancestors {
-            eprintln!("{before:<pad$} -> {after}");
+fn undo_impl(id: Option<u64>, list: bool, dry_run: bool) -> Result<ExitCode, Box<dyn Error>> {
+    if list {
+        for id in journal::ids()? {
+            let batch = journal::load(id)?;
+            eprintln!(
+                "{id}: {} in {}, {} step(s)",
+                batch.time,
+                escape::display(&batch.cwd),
+                batch.steps.len(),
+            );
         }
+
+        return Ok(ExitCode::SUCCESS);
     }
 
-    if failure {
+    let Some(id) = id.or(journal::ids()?.last().copied()) else {
+        eprintln!("no changes to undo");
         return Ok(ExitCode::FAILURE);
-    }
+    };
 
-    let pad = before.iter().map(|p| p.len()).max().unwrap();
-    for (before, after) in Iterator::zip(before.iter().rev(), after.iter().rev()) {
-        if before == after {
-            continue;
+    let batch = journal::load(id)?;
+    std::env::set_current_dir(&batch.cwd)?;
+
+    // make sure nothing changed since the batch was applied
+    let missing = batch.missing_paths(&Os);
+
+    if !missing.is_empty() {
+        eprintln!("paths changed since applying batch {id}, refusing to undo it:");
+        for path in missing {
+            eprintln!("missing {}", escape::display(path));
         }
 
-        eprintln!("{before:<pad$} -> {after}");
-        if !args.dry_run {
-            std::fs::rename(before, after)?
+        return Ok(ExitCode::FAILURE);
+    }
+
+    for (step, _) in batch.steps.iter().filter(|(_, inverse)| inverse.is_none()) {
+        eprintln!("can't undo: {step}");
+    }
+
+    let steps = batch.undo_steps();
+
+    let git = match batch.git {
+        true => match Git::discover()? {
+            Some(git) => Some(git),
+            None => {
+                eprintln!(
+                    "batch {id} was staged in git, but {} is not in a git work tree",
+                    escape::display(&batch.cwd)
+                );
+                return Ok(ExitCode::FAILURE);
+            }
+        },
+        false => None,
+    };
+
+    report::steps(&steps, git.as_ref())?;
+
+    if dry_run {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let transaction = match apply::Transaction::run(Os, git, &steps) {
+        Ok(transaction) => transaction,
+        Err(err) => {
+            report::rolled_back(err, &steps)?;
+            return Ok(ExitCode::FAILURE);
         }
+    };
+
+    if commit(transaction) {
+        return Ok(ExitCode::FAILURE);
     }
 
+    journal::remove(id)?;
+
     Ok(ExitCode::SUCCESS)
 }
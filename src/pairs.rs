@@ -0,0 +1,166 @@
+//! Non-interactive pairs of old and new paths, like the export of a spreadsheet.
+//!
+//! Each line holds a pair, lines containing a tab are split at it and any other line is read as
+//! CSV. CSV fields may be quoted with `"`, quotes inside of them are doubled. Paths are not
+//! escaped.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// Splits a CSV line into its fields, returns `None` if a quoted field is not closed.
+fn csv_fields(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut fields = vec![vec![]];
+    let mut quoted = false;
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        let field = fields.last_mut().unwrap();
+        match byte {
+            b'"' if quoted && bytes.peek() == Some(&b'"') => {
+                field.push(b'"');
+                bytes.next();
+            }
+            b'"' if quoted => quoted = false,
+            b'"' if field.is_empty() => quoted = true,
+            b',' if !quoted => fields.push(vec![]),
+            _ => field.push(byte),
+        }
+    }
+
+    (!quoted).then_some(fields)
+}
+
+/// Reads pairs of old and new paths, empty lines are skipped.
+pub fn read(reader: impl BufRead) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut pairs = vec![];
+    for (idx, line) in reader.split(b'\n').enumerate() {
+        let mut line = line?;
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+
+        // spreadsheets like to start their exports with a byte order mark
+        if idx == 0 && line.starts_with("\u{feff}".as_bytes()) {
+            line.drain(..3);
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields = match line.contains(&b'\t') {
+            true => Some(
+                line.split(|&byte| byte == b'\t')
+                    .map(<[u8]>::to_vec)
+                    .collect(),
+            ),
+            false => csv_fields(&line),
+        };
+
+        let Some([old, new]) = fields.and_then(|fields| <[Vec<u8>; 2]>::try_from(fields).ok())
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} is not a pair of paths", idx + 1),
+            ));
+        };
+
+        if old.is_empty() || new.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} has an empty path", idx + 1),
+            ));
+        }
+
+        pairs.push((
+            PathBuf::from(OsString::from_vec(old)),
+            PathBuf::from(OsString::from_vec(new)),
+        ));
+    }
+
+    Ok(pairs)
+}
+
+/// Reads pairs from the file at `path`, or from `stdin` if `path` is `-`.
+pub fn load(path: &Path, stdin: impl BufRead) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    match path.as_os_str() == "-" {
+        true => read(stdin),
+        false => read(BufReader::new(File::open(path)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(PathBuf, PathBuf)> {
+        pairs
+            .iter()
+            .map(|(old, new)| (PathBuf::from(old), PathBuf::from(new)))
+            .collect()
+    }
+
+    #[test]
+    fn csv_lines_are_split() {
+        let fields = |line: &[u8]| {
+            csv_fields(line).map(|fields| {
+                fields
+                    .into_iter()
+                    .map(|field| String::from_utf8(field).unwrap())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(fields(b"a,b"), Some(vec!["a".into(), "b".into()]));
+        assert_eq!(fields(b"\"a,b\",c"), Some(vec!["a,b".into(), "c".into()]));
+        assert_eq!(
+            fields(b"\"say \"\"hi\"\"\",b\"c"),
+            Some(vec!["say \"hi\"".into(), "b\"c".into()])
+        );
+        assert_eq!(fields(b",,"), Some(vec![String::new(); 3]));
+        assert_eq!(fields(b"\"a,b"), None);
+    }
+
+    #[test]
+    fn pairs_are_read() {
+        let input = "\u{feff}a.txt,b.txt\r\n\r\n\"c, d.txt\",e.txt\r\nf,g\th\n";
+        assert_eq!(
+            read(input.as_bytes()).unwrap(),
+            pairs(&[("a.txt", "b.txt"), ("c, d.txt", "e.txt"), ("f,g", "h")])
+        );
+
+        // the bytes of paths are kept as they are
+        let read_bytes = read(&b"a\xFF\tb\n"[..]).unwrap();
+        assert_eq!(read_bytes[0].0.as_os_str().as_encoded_bytes(), b"a\xFF");
+    }
+
+    #[test]
+    fn invalid_lines_are_refused() {
+        for (input, message) in [
+            ("a,b\nc\n", "line 2 is not a pair of paths"),
+            ("a,b,c\n", "line 1 is not a pair of paths"),
+            ("\"a,b\n", "line 1 is not a pair of paths"),
+            ("a\t\n", "line 1 has an empty path"),
+        ] {
+            let err = read(input.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn pairs_are_loaded_from_stdin_or_files() {
+        let stdin = "a,b\n".as_bytes();
+        assert_eq!(load(Path::new("-"), stdin).unwrap(), pairs(&[("a", "b")]));
+
+        let path = std::env::temp_dir().join(format!("evaki-pairs-load-{}", std::process::id()));
+        std::fs::write(&path, "c\td\n").unwrap();
+        assert_eq!(load(&path, stdin).unwrap(), pairs(&[("c", "d")]));
+        std::fs::remove_file(&path).unwrap();
+
+        let err = load(&path, stdin).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
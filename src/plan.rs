@@ -0,0 +1,1086 @@
+//! Ordering of renames so that chains and cycles can be applied safely.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::{fmt, io};
+
+use crate::copy::CopyMode;
+use crate::escape;
+use crate::trash::Trashed;
+use crate::vfs::Filesystem;
+
+/// A single filesystem operation of an ordered batch of renames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Rename `from` to `to`, nothing in the batch occupies `to` at this point.
+    Rename { from: PathBuf, to: PathBuf },
+
+    /// Swap `a` and `b` with each other.
+    Exchange { a: PathBuf, b: PathBuf },
+
+    /// Copy or link `from` to `to`, directories are copied recursively.
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+        mode: CopyMode,
+    },
+
+    /// Remove the file or directory at `path` recursively.
+    Remove { path: PathBuf },
+
+    /// Move the file or directory at `path` to the trash.
+    Trash { path: PathBuf },
+
+    /// Restore the trashed `file` to `path` and remove its trash `info` file.
+    Restore {
+        file: PathBuf,
+        info: PathBuf,
+        path: PathBuf,
+    },
+
+    /// Create the directory at `path`, its ancestor must exist.
+    CreateDir { path: PathBuf },
+
+    /// Remove the empty directory at `path`.
+    RemoveDir { path: PathBuf },
+}
+
+impl fmt::Display for Step {
+    /// Displays the step, the width is used to pad the first path of two path steps.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pad = f.width().unwrap_or_default();
+        let display = escape::display;
+        match self {
+            Step::Rename { from, to } => {
+                write!(f, "{:<pad$} -> {}", display(from), display(to))
+            }
+            Step::Exchange { a, b } => write!(f, "{:<pad$} <-> {}", display(a), display(b)),
+            Step::Copy { from, to, mode } => {
+                let arrow = match mode {
+                    CopyMode::Copy => "+>",
+                    CopyMode::Hardlink => "=>",
+                    CopyMode::Symlink => "~>",
+                    CopyMode::Reflink => "*>",
+                };
+
+                write!(f, "{:<pad$} {arrow} {}", display(from), display(to))
+            }
+            Step::Remove { path } => write!(f, "rm {}", display(path)),
+            Step::Trash { path } => write!(f, "trash {}", display(path)),
+            Step::Restore { path, .. } => write!(f, "restore {}", display(path)),
+            Step::CreateDir { path } => write!(f, "mkdir {}", display(path)),
+            Step::RemoveDir { path } => write!(f, "rmdir {}", display(path)),
+        }
+    }
+}
+
+/// The changes made to a list of paths.
+#[derive(Debug, Default)]
+pub struct Changes<'a> {
+    /// The `(before, after)` pairs of all paths which were renamed.
+    pub renames: Vec<(&'a Path, &'a Path)>,
+
+    /// The `(from, to)` pairs of all paths which were duplicated, `from` is the path after all
+    /// renames were applied.
+    pub copies: Vec<(&'a Path, &'a Path)>,
+
+    /// How duplicated paths are created.
+    pub copy_mode: CopyMode,
+
+    /// The paths which were removed.
+    pub removals: Vec<&'a Path>,
+
+    /// Whether removed paths are moved to the trash instead of being deleted.
+    pub trash: bool,
+
+    /// Existing paths outside of the batch which are replaced by targets.
+    pub replaced: Vec<&'a Path>,
+
+    /// The suffix replaced paths are moved aside to, if they are not deleted.
+    pub backup_suffix: Option<&'a str>,
+}
+
+impl Step {
+    /// Applies this step to `fs`.
+    pub fn apply(&self, fs: &mut impl Filesystem) -> io::Result<()> {
+        match self {
+            Step::Rename { from, to } => fs.rename(from, to),
+            Step::Exchange { a, b } => fs.exchange(a, b),
+            Step::Copy { from, to, mode } => fs.copy(from, to, *mode),
+            Step::Trash { path } => fs.trash(path).map(|_| ()),
+            Step::Restore { file, info, path } => {
+                let trashed = Trashed {
+                    file: file.clone(),
+                    info: info.clone(),
+                };
+
+                fs.restore(&trashed, path)
+            }
+            Step::CreateDir { path } => fs.create_dir(path),
+            Step::RemoveDir { path } => fs.remove_dir(path),
+            Step::Remove { path } => fs.remove(path),
+        }
+    }
+
+    /// Returns the length of the first path of two path steps, used for padding.
+    pub fn pad(&self) -> Option<usize> {
+        match self {
+            Step::Rename { from, .. } | Step::Copy { from, .. } => {
+                Some(escape::display(from).chars().count())
+            }
+            Step::Exchange { a, .. } => Some(escape::display(a).chars().count()),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the path `path` is moved aside to as a backup, `suffix` is appended to its name.
+pub fn backup_name(path: &Path, suffix: &str) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(suffix);
+    PathBuf::from(backup)
+}
+
+/// Splits `path` into the path without its extension and the extension including its dot.
+///
+/// Names without a dot after their first character, `..` and paths ending in a slash don't have an
+/// extension.
+pub fn split_extension(path: &Path) -> (&OsStr, &OsStr) {
+    let bytes = path.as_os_str().as_bytes();
+    let name = bytes
+        .iter()
+        .rposition(|&byte| byte == b'/')
+        .map_or(0, |idx| idx + 1);
+
+    match bytes[name..].iter().rposition(|&byte| byte == b'.') {
+        Some(dot) if dot > 0 && &bytes[name..] != b".." => {
+            let (stem, extension) = bytes.split_at(name + dot);
+            (OsStr::from_bytes(stem), OsStr::from_bytes(extension))
+        }
+        _ => (path.as_os_str(), OsStr::new("")),
+    }
+}
+
+/// Returns the depth of a path, i.e. the number of its components.
+fn depth(path: &Path) -> usize {
+    path.components().count()
+}
+
+/// Returns a name next to `path` which neither exists in `fs` nor is contained in `taken`.
+pub fn temp_name(path: &Path, taken: &BTreeSet<PathBuf>, fs: &impl Filesystem) -> PathBuf {
+    let name = path.file_name().unwrap_or(path.as_os_str());
+
+    (0..)
+        .map(|n| {
+            let mut temp = OsString::from(".");
+            temp.push(name);
+            temp.push(format!(".evaki~{n}"));
+            path.with_file_name(temp)
+        })
+        .find(|temp| !taken.contains(temp) && !fs.exists(temp))
+        .expect("there is always a free name")
+}
+
+/// Orders the given changes into steps which can be applied to `fs` one after another without any
+/// step clobbering another path of the batch.
+///
+/// Replaced paths are moved aside or removed first, followed by removals, unless they are an
+/// ancestor of a renamed path, renames and copies. Renames are expected to have unique sources
+/// and unique targets. Renames wait for their target and its ancestors to be vacated or renamed
+/// to by other renames, unless an ancestor is the source of an ancestor, they are then renamed
+/// inside of it before it's moved. Sources move along with their renamed ancestors, renames which
+/// are at their target afterwards are dropped. Cycles are broken up using exchanges or temporary
+/// names.
+pub fn order(changes: &Changes, fs: &impl Filesystem) -> Vec<Step> {
+    let renames = &changes.renames;
+    let mut steps = vec![];
+
+    // removed directories may only be empty once their children were moved out
+    let (mut early, mut late): (Vec<_>, Vec<_>) =
+        changes.removals.iter().copied().partition(|removal| {
+            !renames
+                .iter()
+                .any(|(before, _)| before.starts_with(removal))
+        });
+
+    early.sort_by(|a, b| Ord::cmp(&depth(b), &depth(a)).then_with(|| Ord::cmp(b, a)));
+    late.sort_by(|a, b| Ord::cmp(&depth(b), &depth(a)).then_with(|| Ord::cmp(b, a)));
+
+    let remove = |path: &Path| match changes.trash {
+        true => Step::Trash {
+            path: path.to_path_buf(),
+        },
+        false => Step::Remove {
+            path: path.to_path_buf(),
+        },
+    };
+
+    steps.extend(
+        changes
+            .replaced
+            .iter()
+            .map(|path| match changes.backup_suffix {
+                Some(suffix) => Step::Rename {
+                    from: path.to_path_buf(),
+                    to: backup_name(path, suffix),
+                },
+                None => Step::Remove {
+                    path: path.to_path_buf(),
+                },
+            }),
+    );
+    steps.extend(early.into_iter().map(remove));
+    steps.extend(order_renames(renames, fs));
+    steps.extend(changes.copies.iter().map(|(from, to)| Step::Copy {
+        from: from.to_path_buf(),
+        to: to.to_path_buf(),
+        mode: changes.copy_mode,
+    }));
+    steps.extend(late.into_iter().map(remove));
+
+    steps
+}
+
+/// The renames which are not done yet, tracked by where their sources currently are.
+struct Pending<'a> {
+    renames: &'a [(&'a Path, &'a Path)],
+
+    /// The current source of each rename, which moves along with its renamed ancestors.
+    current: Vec<PathBuf>,
+
+    /// The pending renames by their current source.
+    sources: BTreeMap<PathBuf, usize>,
+
+    /// The pending renames by their target.
+    targets: BTreeMap<&'a Path, usize>,
+
+    /// The renames into the sources of each rename's ancestors, which wait for it.
+    parents: Vec<Vec<usize>>,
+
+    /// The number of pending renames into the source of each rename.
+    children: Vec<usize>,
+
+    done: Vec<bool>,
+}
+
+impl Pending<'_> {
+    /// Returns whether the rename `idx` can be applied, its target may still be occupied by
+    /// `other` if both are exchanged.
+    fn is_ready(&self, idx: usize, other: Option<usize>) -> bool {
+        let is_free = self
+            .sources
+            .get(self.renames[idx].1)
+            .is_none_or(|&occupant| Some(occupant) == other);
+
+        !self.done[idx] && self.children[idx] == 0 && is_free && self.is_settled(idx)
+    }
+
+    /// Returns whether the ancestors of the target of rename `idx` are neither moved away nor
+    /// renamed to by any other pending rename, unless it's renamed inside of them first.
+    fn is_settled(&self, idx: usize) -> bool {
+        self.renames[idx]
+            .1
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .all(|ancestor| match self.sources.get(ancestor) {
+                Some(occupant) => self.parents[idx].contains(occupant),
+                None => !self.targets.contains_key(ancestor),
+            })
+    }
+
+    /// Moves the current sources at or inside of each `from` to its `to` at once, returns the
+    /// moved renames and the paths they were at.
+    fn relocate(&mut self, moves: &[(&Path, &Path)]) -> Vec<(usize, PathBuf)> {
+        let mut moved = vec![];
+        for (from, to) in moves {
+            let inside = self
+                .sources
+                .range(from.to_path_buf()..)
+                .take_while(|(source, _)| source.starts_with(from));
+
+            moved.extend(inside.map(|(source, &idx)| {
+                let current = match source.strip_prefix(from).unwrap() {
+                    relative if relative.as_os_str().is_empty() => to.to_path_buf(),
+                    relative => to.join(relative),
+                };
+
+                (idx, source.clone(), current)
+            }));
+        }
+
+        for (_, source, _) in &moved {
+            self.sources.remove(source);
+        }
+
+        moved
+            .into_iter()
+            .map(|(idx, source, current)| {
+                self.sources.insert(current.clone(), idx);
+                self.current[idx] = current;
+                (idx, source)
+            })
+            .collect()
+    }
+
+    /// Marks the rename `idx` as done, its parents no longer wait for it.
+    fn finish(&mut self, idx: usize) {
+        self.done[idx] = true;
+        self.sources.remove(&self.current[idx]);
+        self.targets.remove(self.renames[idx].1);
+        for &parent in &self.parents[idx] {
+            self.children[parent] -= 1;
+        }
+    }
+}
+
+fn order_renames(renames: &[(&Path, &Path)], fs: &impl Filesystem) -> Vec<Step> {
+    let sources: BTreeMap<PathBuf, usize> = renames
+        .iter()
+        .enumerate()
+        .map(|(idx, (before, _))| (before.to_path_buf(), idx))
+        .collect();
+
+    // renames into the source of an ancestor are applied before the ancestor is moved
+    let parents: Vec<Vec<usize>> = renames
+        .iter()
+        .map(|(before, after)| {
+            before
+                .ancestors()
+                .skip(1)
+                .filter(|ancestor| after != ancestor && after.starts_with(ancestor))
+                .filter_map(|ancestor| sources.get(ancestor).copied())
+                .collect()
+        })
+        .collect();
+
+    let mut children = vec![0; renames.len()];
+    for &parent in parents.iter().flatten() {
+        children[parent] += 1;
+    }
+
+    let mut pending = Pending {
+        renames,
+        current: renames
+            .iter()
+            .map(|(before, _)| before.to_path_buf())
+            .collect(),
+        sources,
+        targets: renames
+            .iter()
+            .enumerate()
+            .map(|(idx, (_, after))| (*after, idx))
+            .collect(),
+        parents,
+        children,
+        done: vec![false; renames.len()],
+    };
+
+    // the renames waiting for a path to be vacated or renamed to, it's their target or one of its
+    // ancestors
+    let mut watchers: BTreeMap<&Path, Vec<usize>> = BTreeMap::new();
+    for (idx, (_, after)) in renames.iter().enumerate() {
+        for ancestor in after.ancestors() {
+            if !ancestor.as_os_str().is_empty() {
+                watchers.entry(ancestor).or_default().push(idx);
+            }
+        }
+    }
+
+    let mut taken: BTreeSet<PathBuf> = renames
+        .iter()
+        .flat_map(|(before, after)| [*before, *after])
+        .map(Path::to_path_buf)
+        .collect();
+
+    let mut vacated = vec![false; renames.len()];
+
+    // deepest paths first, such that ties are ordered the same way each time
+    let key = |idx: usize| (Reverse(depth(renames[idx].0)), Reverse(renames[idx].0), idx);
+    let mut order: Vec<_> = (0..renames.len()).map(key).collect();
+    order.sort();
+
+    let mut ready: BTreeSet<_> = (0..renames.len())
+        .filter(|&idx| pending.is_ready(idx, None))
+        .map(key)
+        .collect();
+
+    let mut steps = vec![];
+    let mut remaining = renames.len();
+
+    while remaining > 0 {
+        // the step and the renames it applies
+        let (step, applied) = if let Some((_, _, idx)) = ready.pop_first() {
+            // readiness is checked again, as other renames may have moved its source
+            if !pending.is_ready(idx, None) {
+                continue;
+            }
+
+            let step = Step::Rename {
+                from: pending.current[idx].clone(),
+                to: renames[idx].1.to_path_buf(),
+            };
+
+            (step, vec![idx])
+        } else {
+            // the renames wait for each other, the deepest one without children which is in the
+            // way of another one is moved aside
+            let in_the_way = |idx: usize| {
+                let current = &pending.current[idx];
+                watchers.get(current.as_path()).is_some_and(|watchers| {
+                    watchers.iter().any(|&other| {
+                        !pending.done[other] && !pending.parents[other].contains(&idx)
+                    })
+                })
+            };
+
+            let start = order.iter().map(|&(_, _, idx)| idx).find(|&idx| {
+                !pending.done[idx] && !vacated[idx] && pending.children[idx] == 0 && in_the_way(idx)
+            });
+
+            // two renames which only wait for each other are exchanged
+            let pair = start.and_then(|start| {
+                let next = *pending.sources.get(renames[start].1)?;
+                (renames[next].1 == pending.current[start]
+                    && pending.is_ready(start, Some(next))
+                    && pending.is_ready(next, Some(start)))
+                .then_some((start, next))
+            });
+
+            if let Some((start, next)) = pair {
+                let step = Step::Exchange {
+                    a: pending.current[start].clone(),
+                    b: pending.current[next].clone(),
+                };
+
+                (step, vec![start, next])
+            } else if let Some(start) = start {
+                let temp = temp_name(&pending.current[start], &taken, fs);
+                taken.insert(temp.clone());
+                vacated[start] = true;
+
+                let step = Step::Rename {
+                    from: pending.current[start].clone(),
+                    to: temp,
+                };
+
+                (step, vec![])
+            } else {
+                // the renames can't be ordered, the first one is applied anyway and fails
+                let (_, _, idx) = *order
+                    .iter()
+                    .find(|&&(_, _, idx)| !pending.done[idx])
+                    .unwrap();
+
+                let step = Step::Rename {
+                    from: pending.current[idx].clone(),
+                    to: renames[idx].1.to_path_buf(),
+                };
+
+                (step, vec![idx])
+            }
+        };
+
+        let moved = match &step {
+            Step::Rename { from, to } => pending.relocate(&[(from, to)]),
+            Step::Exchange { a, b } => pending.relocate(&[(a, b), (b, a)]),
+            _ => unreachable!("only renames and exchanges are ordered"),
+        };
+
+        let mut changed = vec![];
+        for idx in applied {
+            pending.finish(idx);
+            changed.extend(&pending.parents[idx]);
+            changed.extend(watchers.get(renames[idx].1).into_iter().flatten());
+            remaining -= 1;
+        }
+
+        for (idx, source) in moved {
+            changed.push(idx);
+            changed.extend(watchers.get(source.as_path()).into_iter().flatten());
+            changed.extend(
+                watchers
+                    .get(pending.current[idx].as_path())
+                    .into_iter()
+                    .flatten(),
+            );
+        }
+
+        // renames which were moved along onto their target are done once it's settled
+        while let Some(idx) = changed.pop() {
+            if pending.done[idx] {
+                continue;
+            }
+
+            if pending.current[idx] == renames[idx].1 && pending.is_settled(idx) {
+                pending.finish(idx);
+                changed.extend(&pending.parents[idx]);
+                changed.extend(watchers.get(renames[idx].1).into_iter().flatten());
+                remaining -= 1;
+            } else if pending.is_ready(idx, None) {
+                ready.insert(key(idx));
+            }
+        }
+
+        steps.push(step);
+    }
+
+    steps
+}
+
+/// Returns the cycles of `renames`, in which each rename targets the source of the next, as
+/// indices into `renames`.
+pub fn cycles(renames: &[(&Path, &Path)]) -> Vec<Vec<usize>> {
+    let sources: BTreeMap<&Path, usize> = renames
+        .iter()
+        .enumerate()
+        .map(|(idx, (before, _))| (*before, idx))
+        .collect();
+
+    let next: Vec<Option<usize>> = renames
+        .iter()
+        .map(|(_, after)| sources.get(after).copied())
+        .collect();
+
+    // each rename is visited once, a cycle is found if a walk reaches itself
+    let mut walks: Vec<Option<usize>> = vec![None; renames.len()];
+    let mut cycles = vec![];
+
+    for start in 0..renames.len() {
+        let mut current = Some(start);
+        while let Some(idx) = current.filter(|&idx| walks[idx].is_none()) {
+            walks[idx] = Some(start);
+            current = next[idx];
+        }
+
+        if let Some(first) = current.filter(|&idx| walks[idx] == Some(start)) {
+            let mut cycle = vec![first];
+            while let Some(idx) = next[*cycle.last().unwrap()].filter(|&idx| idx != first) {
+                cycle.push(idx);
+            }
+
+            cycles.push(cycle);
+        }
+    }
+
+    cycles
+}
+
+/// Returns the directories of `fs` which are left empty once the given changes are applied,
+/// children come before their ancestors.
+pub fn emptied_dirs(changes: &Changes, fs: &impl Filesystem) -> io::Result<Vec<PathBuf>> {
+    let sources: BTreeSet<&Path> = Iterator::chain(
+        changes.renames.iter().map(|(before, _)| *before),
+        changes.removals.iter().copied(),
+    )
+    .collect();
+
+    let targets: BTreeSet<&Path> = Iterator::chain(
+        changes.renames.iter().map(|(_, after)| *after),
+        changes.copies.iter().map(|(_, to)| *to),
+    )
+    .collect();
+
+    let ancestor = |path: &Path| -> Option<PathBuf> {
+        path.parent()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+    };
+
+    // candidates are visited deepest first, such that emptied children are known for ancestors
+    let mut candidates: BTreeSet<(Reverse<usize>, PathBuf)> = sources
+        .iter()
+        .filter_map(|source| ancestor(source))
+        .map(|dir| (Reverse(dir.components().count()), dir))
+        .collect();
+
+    let mut emptied = vec![];
+    let mut removed = BTreeSet::new();
+
+    while let Some((_, dir)) = candidates.pop_first() {
+        // renamed directories are not left behind, directories moved into are not empty
+        if sources.contains(dir.as_path()) || targets.iter().any(|target| target.starts_with(&dir))
+        {
+            continue;
+        }
+
+        let mut is_empty = true;
+        for name in fs.read_dir(&dir)? {
+            let entry = dir.join(name);
+            if !sources.contains(entry.as_path()) && !removed.contains(&entry) {
+                is_empty = false;
+                break;
+            }
+        }
+
+        if is_empty {
+            if let Some(ancestor) = ancestor(&dir) {
+                candidates.insert((Reverse(ancestor.components().count()), ancestor));
+            }
+
+            emptied.push(dir.clone());
+            removed.insert(dir);
+        }
+    }
+
+    Ok(emptied)
+}
+
+/// What an earlier step left at a path.
+#[derive(Debug, Clone)]
+enum Entry {
+    /// An empty directory or a symlink.
+    Created,
+
+    /// The contents `from` had in the filesystem.
+    Moved(PathBuf),
+
+    /// Nothing.
+    Removed,
+}
+
+/// The paths changed by steps, on top of the filesystem they are applied to.
+#[derive(Debug, Default)]
+struct Overlay {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Overlay {
+    /// Returns whether `path` exists once the applied steps are done.
+    fn exists(&self, path: &Path, fs: &impl Filesystem) -> bool {
+        for ancestor in path.ancestors() {
+            match self.entries.get(ancestor) {
+                None => continue,
+                Some(Entry::Removed) => return false,
+                Some(Entry::Created) => return ancestor == path,
+                Some(Entry::Moved(_)) if ancestor == path => return true,
+                Some(Entry::Moved(from)) => {
+                    return fs.exists(&from.join(path.strip_prefix(ancestor).unwrap()));
+                }
+            }
+        }
+
+        fs.exists(path)
+    }
+
+    /// Returns the entries of `path` and its descendants relative to `path`.
+    fn subtree(&self, path: &Path) -> Vec<(PathBuf, Entry)> {
+        let mut subtree: Vec<_> = self
+            .entries
+            .range(path.to_path_buf()..)
+            .take_while(|(descendant, _)| descendant.starts_with(path))
+            .map(|(descendant, entry)| {
+                let relative = descendant.strip_prefix(path).unwrap();
+                (relative.to_path_buf(), entry.clone())
+            })
+            .collect();
+
+        if !self.entries.contains_key(path) {
+            subtree.insert(0, (PathBuf::new(), Entry::Moved(path.to_path_buf())));
+        }
+
+        subtree
+    }
+
+    /// Replaces `path` and its descendants with `entries` relative to `path`.
+    fn put(&mut self, path: &Path, entries: Vec<(PathBuf, Entry)>) {
+        let descendants: Vec<_> = self
+            .entries
+            .range(path.to_path_buf()..)
+            .map(|(descendant, _)| descendant)
+            .take_while(|descendant| descendant.starts_with(path))
+            .cloned()
+            .collect();
+
+        for descendant in descendants {
+            self.entries.remove(&descendant);
+        }
+
+        for (relative, entry) in entries {
+            match relative.as_os_str().is_empty() {
+                true => self.entries.insert(path.to_path_buf(), entry),
+                false => self.entries.insert(path.join(relative), entry),
+            };
+        }
+    }
+
+    /// Records the changes of `step`.
+    fn apply(&mut self, step: &Step) {
+        match step {
+            Step::Rename { from, to } => {
+                let moved = self.subtree(from);
+                self.put(from, vec![(PathBuf::new(), Entry::Removed)]);
+                self.put(to, moved);
+            }
+            Step::Exchange { a, b } => {
+                let (a_entries, b_entries) = (self.subtree(a), self.subtree(b));
+                self.put(a, b_entries);
+                self.put(b, a_entries);
+            }
+            Step::Copy { to, mode, .. } if *mode == CopyMode::Symlink => {
+                self.put(to, vec![(PathBuf::new(), Entry::Created)]);
+            }
+            Step::Copy { from, to, .. } => self.put(to, self.subtree(from)),
+            Step::Restore { file, path, .. } => {
+                self.put(path, vec![(PathBuf::new(), Entry::Moved(file.clone()))]);
+            }
+            Step::CreateDir { path } => self.put(path, vec![(PathBuf::new(), Entry::Created)]),
+            Step::Remove { path } | Step::Trash { path } | Step::RemoveDir { path } => {
+                self.put(path, vec![(PathBuf::new(), Entry::Removed)]);
+            }
+        }
+    }
+}
+
+/// Inserts steps to create the missing ancestors of all targets right before they are needed.
+///
+/// The changes of earlier steps are tracked, as they aren't applied to `fs` yet when the steps are
+/// only shown. Paths which a later step renames to are never created.
+pub fn create_ancestors(steps: Vec<Step>, fs: &impl Filesystem) -> Vec<Step> {
+    let mut pending: BTreeMap<PathBuf, usize> = BTreeMap::new();
+    for step in &steps {
+        if let Step::Rename { to, .. } = step {
+            *pending.entry(to.clone()).or_default() += 1;
+        }
+    }
+
+    let mut overlay = Overlay::default();
+    let mut with_ancestors = vec![];
+
+    for step in steps {
+        if let Step::Rename { to, .. } = &step {
+            if let Some(count) = pending.get_mut(to) {
+                *count -= 1;
+                if *count == 0 {
+                    pending.remove(to);
+                }
+            }
+        }
+
+        if let Step::Rename { to, .. } | Step::Copy { to, .. } = &step {
+            let mut missing: Vec<_> = to
+                .ancestors()
+                .skip(1)
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .take_while(|ancestor| {
+                    !overlay.exists(ancestor, fs) && !pending.contains_key(*ancestor)
+                })
+                .map(Path::to_path_buf)
+                .collect();
+
+            // outer most ancestors must be created first
+            missing.reverse();
+            for ancestor in missing {
+                let step = Step::CreateDir { path: ancestor };
+                overlay.apply(&step);
+                with_ancestors.push(step);
+            }
+        }
+
+        overlay.apply(&step);
+        with_ancestors.push(step);
+    }
+
+    with_ancestors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{Memory, Node};
+
+    fn file(contents: &str) -> Node {
+        Node::File(contents.as_bytes().to_vec())
+    }
+
+    fn rename(from: &str, to: &str) -> Step {
+        Step::Rename {
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+
+    /// Orders `renames` with missing ancestors and applies them to `fs`.
+    fn apply(fs: &mut Memory, renames: &[(&str, &str)]) -> Vec<Step> {
+        let changes = Changes {
+            renames: renames
+                .iter()
+                .map(|(before, after)| (Path::new(before), Path::new(after)))
+                .collect(),
+            ..Default::default()
+        };
+
+        let steps = create_ancestors(order(&changes, fs), fs);
+        for step in &steps {
+            step.apply(fs).unwrap_or_else(|err| panic!("{step}: {err}"));
+        }
+
+        steps
+    }
+
+    #[test]
+    fn renames_following_their_ancestor_are_dropped() {
+        let mut fs = Memory::new();
+        fs.insert("d/x", file("x"));
+        fs.insert("d/y", file("y"));
+
+        let steps = apply(&mut fs, &[("d", "e"), ("d/x", "e/x"), ("d/y", "e/y")]);
+
+        assert_eq!(steps, [rename("d", "e")]);
+        assert_eq!(fs.get("e/x"), Some(&file("x")));
+        assert_eq!(fs.get("e/y"), Some(&file("y")));
+        assert!(!fs.exists(Path::new("d")));
+    }
+
+    #[test]
+    fn children_are_renamed_inside_their_renamed_ancestor() {
+        let mut fs = Memory::new();
+        fs.insert("d/x", file("x"));
+        fs.insert("d/y", file("y"));
+
+        let steps = apply(&mut fs, &[("d", "e"), ("d/x", "e/z")]);
+
+        assert_eq!(steps, [rename("d", "e"), rename("e/x", "e/z")]);
+        assert_eq!(fs.get("e/z"), Some(&file("x")));
+        assert_eq!(fs.get("e/y"), Some(&file("y")));
+    }
+
+    #[test]
+    fn renames_into_a_target_wait_for_it() {
+        let mut fs = Memory::new();
+        fs.insert("a/x", file("x"));
+        fs.insert("b", file("b"));
+
+        let steps = apply(&mut fs, &[("b", "e/b"), ("a", "e")]);
+
+        assert_eq!(steps, [rename("a", "e"), rename("b", "e/b")]);
+        assert_eq!(fs.get("e/x"), Some(&file("x")));
+        assert_eq!(fs.get("e/b"), Some(&file("b")));
+    }
+
+    #[test]
+    fn missing_ancestors_are_created() {
+        let mut fs = Memory::new();
+        fs.insert("a", file("a"));
+
+        let steps = apply(&mut fs, &[("a", "x/y/a")]);
+
+        assert_eq!(
+            steps,
+            [
+                Step::CreateDir { path: "x".into() },
+                Step::CreateDir { path: "x/y".into() },
+                rename("a", "x/y/a"),
+            ]
+        );
+        assert_eq!(fs.get("x/y/a"), Some(&file("a")));
+    }
+
+    #[test]
+    fn renames_into_a_renamed_source_wait_for_its_chain() {
+        let mut fs = Memory::new();
+        fs.insert("x/d", file("d"));
+        fs.insert("y/e", file("e"));
+
+        apply(&mut fs, &[("x", "y"), ("y", "q"), ("x/d", "y/b")]);
+
+        assert_eq!(fs.get("y/b"), Some(&file("d")));
+        assert_eq!(fs.get("q/e"), Some(&file("e")));
+        assert_eq!(fs.nodes().count(), 4);
+    }
+
+    #[test]
+    fn children_follow_their_exchanged_ancestor() {
+        let mut fs = Memory::new();
+        fs.insert("x/a", file("a"));
+        fs.insert("y/b", file("b"));
+
+        apply(
+            &mut fs,
+            &[("x", "y"), ("y", "x"), ("x/a", "y/c"), ("y/b", "y/d")],
+        );
+
+        assert_eq!(fs.get("y/c"), Some(&file("a")));
+        assert_eq!(fs.get("x/d"), Some(&file("b")));
+        assert_eq!(fs.nodes().count(), 4);
+    }
+
+    #[test]
+    fn renames_into_a_moved_source_wait_for_it() {
+        let mut fs = Memory::new();
+        fs.insert("d/a/b", file("b"));
+        fs.insert("d/c/a", file("a"));
+
+        apply(&mut fs, &[("d/c", "a/d"), ("d/a/b", "d/c/b")]);
+
+        assert_eq!(fs.get("d/c/b"), Some(&file("b")));
+        assert_eq!(fs.get("a/d/a"), Some(&file("a")));
+    }
+
+    #[test]
+    fn sources_moved_into_each_other_go_through_a_temporary_name() {
+        let mut fs = Memory::new();
+        fs.insert("c/x", file("c"));
+        fs.insert("d/y", file("d"));
+
+        apply(&mut fs, &[("c", "d/a"), ("d", "c/c")]);
+
+        assert_eq!(fs.get("d/a/x"), Some(&file("c")));
+        assert_eq!(fs.get("c/c/y"), Some(&file("d")));
+        assert_eq!(fs.nodes().count(), 6);
+    }
+
+    #[test]
+    fn children_moved_below_themselves_go_through_a_temporary_name() {
+        let mut fs = Memory::new();
+        fs.insert("a/c", file("c"));
+
+        apply(&mut fs, &[("a", "b"), ("a/c", "b/c/a")]);
+
+        assert_eq!(fs.get("b/c/a"), Some(&file("c")));
+        assert_eq!(fs.nodes().count(), 3);
+    }
+
+    #[test]
+    fn later_targets_are_not_created() {
+        let mut fs = Memory::new();
+        fs.insert("d/x", file("x"));
+
+        let steps = vec![rename("d/x", "e/x"), rename("d", "e")];
+        assert_eq!(create_ancestors(steps.clone(), &fs), steps);
+    }
+
+    #[test]
+    fn chains_wait_for_children() {
+        let mut fs = Memory::new();
+        fs.insert("d/c", file("c"));
+        fs.insert("x/y/f", file("f"));
+
+        let steps = apply(&mut fs, &[("d", "e"), ("d/c", "d/k"), ("x/y/f", "d")]);
+
+        assert_eq!(
+            steps,
+            [rename("d/c", "d/k"), rename("d", "e"), rename("x/y/f", "d"),]
+        );
+        assert_eq!(fs.get("e/k"), Some(&file("c")));
+        assert_eq!(fs.get("d"), Some(&file("f")));
+    }
+
+    #[test]
+    fn chains_are_vacated_first() {
+        let mut fs = Memory::new();
+        fs.insert("a", file("a"));
+        fs.insert("b", file("b"));
+
+        let steps = apply(&mut fs, &[("a", "b"), ("b", "c")]);
+
+        assert_eq!(steps, [rename("b", "c"), rename("a", "b")]);
+        assert_eq!(fs.get("b"), Some(&file("a")));
+        assert_eq!(fs.get("c"), Some(&file("b")));
+    }
+
+    #[test]
+    fn swaps_are_exchanged() {
+        let mut fs = Memory::new();
+        fs.insert("a", file("a"));
+        fs.insert("b", file("b"));
+
+        let steps = apply(&mut fs, &[("a", "b"), ("b", "a")]);
+
+        assert_eq!(
+            steps,
+            [Step::Exchange {
+                a: "b".into(),
+                b: "a".into()
+            }]
+        );
+        assert_eq!(fs.get("a"), Some(&file("b")));
+        assert_eq!(fs.get("b"), Some(&file("a")));
+    }
+
+    #[test]
+    fn cycles_go_through_a_temporary_name() {
+        let mut fs = Memory::new();
+        fs.insert("a", file("a"));
+        fs.insert("b", file("b"));
+        fs.insert("c", file("c"));
+
+        let steps = apply(&mut fs, &[("a", "b"), ("b", "c"), ("c", "a")]);
+
+        assert_eq!(
+            steps,
+            [
+                rename("c", ".c.evaki~0"),
+                rename("b", "c"),
+                rename("a", "b"),
+                rename(".c.evaki~0", "a"),
+            ]
+        );
+        assert_eq!(fs.get("a"), Some(&file("c")));
+        assert_eq!(fs.get("b"), Some(&file("a")));
+        assert_eq!(fs.get("c"), Some(&file("b")));
+        assert_eq!(fs.nodes().count(), 3);
+    }
+
+    #[test]
+    fn cycle_members_wait_for_children() {
+        let mut fs = Memory::new();
+        fs.insert("a/x", file("x"));
+        fs.insert("b", file("b"));
+
+        let steps = apply(&mut fs, &[("a", "b"), ("b", "a"), ("a/x", "a/y")]);
+
+        assert_eq!(steps[0], rename("a/x", "a/y"));
+        assert_eq!(fs.get("b/y"), Some(&file("x")));
+        assert_eq!(fs.get("a"), Some(&file("b")));
+    }
+
+    #[test]
+    fn targets_of_children_are_vacated_first() {
+        let mut fs = Memory::new();
+        fs.insert("y/a", file("a"));
+        fs.insert("y/c", file("c"));
+        fs.insert("d", file("d"));
+
+        apply(&mut fs, &[("y", "q"), ("y/a", "d"), ("d", "q/b")]);
+
+        assert_eq!(fs.get("d"), Some(&file("a")));
+        assert_eq!(fs.get("q/b"), Some(&file("d")));
+        assert_eq!(fs.get("q/c"), Some(&file("c")));
+        assert_eq!(fs.nodes().count(), 4);
+    }
+
+    #[test]
+    fn cycles_are_found() {
+        let renames = [
+            (Path::new("a"), Path::new("b")),
+            (Path::new("b"), Path::new("a")),
+            (Path::new("c"), Path::new("d")),
+            (Path::new("d"), Path::new("e")),
+            (Path::new("e"), Path::new("c")),
+            (Path::new("f"), Path::new("g")),
+        ];
+
+        assert_eq!(cycles(&renames), [vec![0, 1], vec![2, 3, 4]]);
+    }
+
+    #[test]
+    fn directories_with_nested_targets_are_not_emptied() {
+        let mut fs = Memory::new();
+        fs.insert("x/b", file("b"));
+        fs.insert("y/c", file("c"));
+
+        let changes = Changes {
+            renames: vec![
+                (Path::new("x/b"), Path::new("x/z/d")),
+                (Path::new("y/c"), Path::new("c")),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(emptied_dirs(&changes, &fs).unwrap(), [PathBuf::from("y")]);
+    }
+}
@@ -0,0 +1,465 @@
+//! Plans renaming a list of paths, which are validated and applied as a whole.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::{error, fmt, io};
+
+use crate::apply::Transaction;
+use crate::copy::CopyMode;
+use crate::git::Git;
+use crate::plan::{self, Changes, Step};
+use crate::validate::{self, Diagnostics, Location, Problem, Rules, Warning};
+use crate::vfs::Filesystem;
+
+/// How the paths of a plan are changed.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// How paths are duplicated instead of renamed, if they are.
+    pub copy_mode: Option<CopyMode>,
+
+    /// Whether ids without any paths are removed.
+    pub delete: bool,
+
+    /// Whether removed paths are moved to the trash instead of being deleted.
+    pub trash: bool,
+
+    /// Whether paths may be moved to other directories, missing ancestors are created.
+    pub move_files: bool,
+
+    /// Whether changing the extension of a file is refused.
+    pub stem_only: bool,
+
+    /// Whether existing paths outside of the plan are replaced.
+    pub force: bool,
+
+    /// The suffix existing paths outside of the plan are moved aside to, instead of replacing
+    /// them.
+    pub backup_suffix: Option<String>,
+
+    /// Whether directories which are left empty are removed.
+    pub prune: bool,
+}
+
+impl Options {
+    /// Returns which edits are allowed.
+    pub fn rules(&self) -> Rules {
+        Rules {
+            copy: self.copy_mode.is_some(),
+            delete: self.delete,
+            move_files: self.move_files,
+            stem_only: self.stem_only,
+        }
+    }
+}
+
+/// The paths of a batch before and after changing them.
+///
+/// Each path before is identified by its index, its id. The first path after of an id is its new
+/// path and any further paths are copies of it, ids without paths after are removed. If paths are
+/// only copied, all paths after are copies of the path before.
+#[derive(Debug, Clone)]
+pub struct RenamePlan {
+    before: Vec<PathBuf>,
+    after: Vec<Vec<PathBuf>>,
+    options: Options,
+}
+
+/// The error of applying a plan.
+#[derive(Debug)]
+pub enum ApplyError {
+    /// The plan has problems, nothing was applied.
+    Invalid(Vec<Problem>),
+
+    /// Planning the steps failed, nothing was applied.
+    Io(io::Error),
+
+    /// A step failed, the applied steps were rolled back.
+    Failed {
+        /// The step which failed and its index among the applied steps.
+        step: Step,
+        idx: usize,
+
+        /// The error of the step.
+        err: io::Error,
+
+        /// The applied steps, their inverses and the results of rolling them back.
+        rollback: Vec<(Step, Step, io::Result<()>)>,
+    },
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::Invalid(problems) => {
+                write!(f, "the plan has {} problem(s)", problems.len())
+            }
+            ApplyError::Io(err) => write!(f, "{err}"),
+            ApplyError::Failed { step, err, .. } => write!(f, "{step}: {err}"),
+        }
+    }
+}
+
+impl error::Error for ApplyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ApplyError::Invalid(_) => None,
+            ApplyError::Io(err) | ApplyError::Failed { err, .. } => Some(err),
+        }
+    }
+}
+
+impl RenamePlan {
+    /// Creates a plan changing each `before` path to the `after` paths of its id.
+    ///
+    /// # Panics
+    /// Panics if `before` and `after` differ in length.
+    pub fn new(before: Vec<PathBuf>, after: Vec<Vec<PathBuf>>, options: Options) -> Self {
+        assert_eq!(before.len(), after.len(), "each path needs an id");
+        Self {
+            before,
+            after,
+            options,
+        }
+    }
+
+    /// Creates a plan from `(before, after)` pairs, pairs with the same path before share an id.
+    pub fn from_pairs(
+        pairs: impl IntoIterator<Item = (PathBuf, PathBuf)>,
+        options: Options,
+    ) -> Self {
+        let mut ids: BTreeMap<PathBuf, usize> = BTreeMap::new();
+        let mut before = vec![];
+        let mut after: Vec<Vec<PathBuf>> = vec![];
+
+        for (from, to) in pairs {
+            match ids.get(&from) {
+                Some(&idx) => after[idx].push(to),
+                None => {
+                    ids.insert(from.clone(), before.len());
+                    before.push(from);
+                    after.push(vec![to]);
+                }
+            }
+        }
+
+        Self::new(before, after, options)
+    }
+
+    /// Returns the paths before.
+    pub fn before(&self) -> &[PathBuf] {
+        &self.before
+    }
+
+    /// Returns the paths after of each id.
+    pub fn after(&self) -> &[Vec<PathBuf>] {
+        &self.after
+    }
+
+    /// Returns the options of this plan.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Returns the path after at `at`.
+    fn path(&self, (idx, nth): Location) -> &Path {
+        &self.after[idx][nth]
+    }
+
+    /// Returns the changes of this plan, replaced paths are not included.
+    fn changes(&self) -> Changes<'_> {
+        let pairs = || Iterator::zip(self.before.iter(), self.after.iter());
+
+        match self.options.copy_mode {
+            None => Changes {
+                renames: pairs()
+                    .filter_map(|(before, paths)| Some((before, paths.first()?)))
+                    .filter(|(before, after)| before != after)
+                    .map(|(before, after)| (before.as_path(), after.as_path()))
+                    .collect(),
+                copies: self
+                    .after
+                    .iter()
+                    .filter_map(|paths| paths.split_first())
+                    .flat_map(|(first, rest)| rest.iter().map(|to| (first.as_path(), to.as_path())))
+                    .collect(),
+                copy_mode: CopyMode::Copy,
+                removals: pairs()
+                    .filter(|(_, paths)| paths.is_empty())
+                    .map(|(before, _)| before.as_path())
+                    .collect(),
+                trash: self.options.trash,
+                ..Default::default()
+            },
+            Some(copy_mode) => Changes {
+                copies: pairs()
+                    .flat_map(|(before, paths)| paths.iter().map(move |after| (before, after)))
+                    .filter(|(before, after)| before != after)
+                    .map(|(before, after)| (before.as_path(), after.as_path()))
+                    .collect(),
+                copy_mode,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Returns the locations of paths after which replace existing paths of `fs` outside of this
+    /// plan.
+    fn conflicts(&self, fs: &impl Filesystem) -> Vec<Location> {
+        let sources: BTreeSet<&Path> = self.before.iter().map(PathBuf::as_path).collect();
+
+        let mut conflicts = vec![];
+        for (idx, (before, paths)) in
+            Iterator::zip(self.before.iter(), self.after.iter()).enumerate()
+        {
+            for (nth, after) in paths.iter().enumerate() {
+                if after != before && !sources.contains(after.as_path()) && fs.exists(after) {
+                    conflicts.push((idx, nth));
+                }
+            }
+        }
+
+        conflicts
+    }
+
+    /// Returns the cycles of renamed ids.
+    fn cycles(&self) -> Vec<Vec<usize>> {
+        if self.options.copy_mode.is_some() {
+            return vec![];
+        }
+
+        let (ids, renames): (Vec<usize>, Vec<(&Path, &Path)>) =
+            Iterator::zip(self.before.iter(), self.after.iter())
+                .enumerate()
+                .filter_map(|(idx, (before, paths))| Some((idx, (before, paths.first()?))))
+                .filter(|(_, (before, after))| before != after)
+                .map(|(idx, (before, after))| (idx, (before.as_path(), after.as_path())))
+                .unzip();
+
+        plan::cycles(&renames)
+            .into_iter()
+            .map(|cycle| cycle.into_iter().map(|idx| ids[idx]).collect())
+            .collect()
+    }
+
+    /// Validates this plan against the paths of `fs`.
+    pub fn validate(&self, fs: &impl Filesystem) -> Diagnostics {
+        let mut problems = validate::validate(self.options.rules(), &self.before, &self.after, fs);
+
+        let conflicts = self.conflicts(fs);
+        match &self.options.backup_suffix {
+            Some(suffix) => {
+                for at in conflicts {
+                    let backup = plan::backup_name(self.path(at), suffix);
+                    if fs.exists(&backup) {
+                        problems.push(Problem::ExistingBackup { at, backup });
+                    }
+                }
+            }
+            None if !self.options.force => {
+                problems.extend(conflicts.into_iter().map(|at| Problem::Conflict { at }));
+            }
+            None => {}
+        }
+
+        let mut warnings = vec![];
+        if !self.options.stem_only {
+            warnings.extend(
+                validate::changed_extensions(&self.before, &self.after, fs)
+                    .into_iter()
+                    .map(|at| Warning::ChangedExtension { at }),
+            );
+        }
+
+        warnings.extend(self.cycles().into_iter().map(|ids| Warning::Cycle { ids }));
+
+        Diagnostics { problems, warnings }
+    }
+
+    /// Returns the steps applying this plan to `fs`, the plan must be valid.
+    pub fn steps(&self, fs: &impl Filesystem) -> io::Result<Vec<Step>> {
+        let mut changes = self.changes();
+
+        if self.options.force || self.options.backup_suffix.is_some() {
+            let replaced: BTreeSet<&Path> = self
+                .conflicts(fs)
+                .into_iter()
+                .map(|at| self.path(at))
+                .collect();
+
+            changes.replaced = replaced.into_iter().collect();
+            changes.backup_suffix = self.options.backup_suffix.as_deref();
+        }
+
+        let mut steps = plan::order(&changes, fs);
+
+        if self.options.move_files {
+            steps = plan::create_ancestors(steps, fs);
+        }
+
+        if self.options.prune {
+            let emptied = plan::emptied_dirs(&changes, fs)?;
+            steps.extend(emptied.into_iter().map(|path| Step::RemoveDir { path }));
+        }
+
+        Ok(steps)
+    }
+
+    /// Validates and applies this plan to `fs` in a transaction, which must be committed to
+    /// remove deleted paths. Renames of tracked files are staged in the index of `git`.
+    pub fn apply<F: Filesystem>(
+        &self,
+        fs: F,
+        git: Option<Git>,
+    ) -> Result<Transaction<F>, ApplyError> {
+        let diagnostics = self.validate(&fs);
+        if !diagnostics.is_valid() {
+            return Err(ApplyError::Invalid(diagnostics.problems));
+        }
+
+        let steps = self.steps(&fs).map_err(ApplyError::Io)?;
+        Transaction::run(fs, git, &steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{Memory, Node};
+
+    fn file(contents: &str) -> Node {
+        Node::File(contents.as_bytes().to_vec())
+    }
+
+    fn fs() -> Memory {
+        let mut fs = Memory::new();
+        fs.insert("a", file("a"));
+        fs.insert("b", file("b"));
+        fs.insert("c", file("c"));
+        fs
+    }
+
+    fn plan(pairs: &[(&str, &str)], options: Options) -> RenamePlan {
+        RenamePlan::from_pairs(
+            pairs
+                .iter()
+                .map(|(before, after)| (PathBuf::from(before), PathBuf::from(after))),
+            options,
+        )
+    }
+
+    #[test]
+    fn pairs_with_the_same_source_share_an_id() {
+        let plan = plan(&[("a", "x"), ("b", "y"), ("a", "z")], Options::default());
+
+        assert_eq!(plan.before(), [PathBuf::from("a"), PathBuf::from("b")]);
+        assert_eq!(
+            plan.after(),
+            [
+                vec![PathBuf::from("x"), PathBuf::from("z")],
+                vec![PathBuf::from("y")]
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_cycles() {
+        let mut fs = fs();
+        let plan = plan(&[("a", "b"), ("b", "c"), ("c", "a")], Options::default());
+
+        let diagnostics = plan.validate(&fs);
+        assert!(diagnostics.is_valid());
+        assert_eq!(
+            diagnostics.warnings,
+            [Warning::Cycle { ids: vec![0, 1, 2] }]
+        );
+
+        let transaction = plan.apply(&mut fs, None).unwrap();
+        assert!(transaction.commit().is_empty());
+
+        assert_eq!(fs.get("a"), Some(&file("c")));
+        assert_eq!(fs.get("b"), Some(&file("a")));
+        assert_eq!(fs.get("c"), Some(&file("b")));
+    }
+
+    #[test]
+    fn conflicts_are_invalid() {
+        let mut fs = fs();
+        let plan = plan(&[("a", "c")], Options::default());
+
+        let Err(ApplyError::Invalid(problems)) = plan.apply(&mut fs, None) else {
+            panic!("conflicts must be refused");
+        };
+        assert_eq!(problems, [Problem::Conflict { at: (0, 0) }]);
+        assert_eq!(fs.get("a"), Some(&file("a")));
+    }
+
+    #[test]
+    fn conflicts_are_backed_up() {
+        let mut fs = fs();
+        let options = Options {
+            backup_suffix: Some("~".into()),
+            ..Options::default()
+        };
+
+        let transaction = plan(&[("a", "c")], options).apply(&mut fs, None).unwrap();
+        assert!(transaction.commit().is_empty());
+
+        assert_eq!(fs.get("c"), Some(&file("a")));
+        assert_eq!(fs.get("c~"), Some(&file("c")));
+    }
+
+    #[test]
+    fn removals_happen_on_commit() {
+        let mut fs = fs();
+        let options = Options {
+            delete: true,
+            ..Options::default()
+        };
+        let plan = RenamePlan::new(
+            vec!["a".into(), "b".into()],
+            vec![vec![], vec!["a".into()]],
+            options,
+        );
+
+        let transaction = plan.apply(&mut fs, None).unwrap();
+        assert!(transaction.commit().is_empty());
+
+        assert_eq!(fs.get("a"), Some(&file("b")));
+        assert_eq!(fs.nodes().count(), 2);
+    }
+
+    #[test]
+    fn failures_are_rolled_back() {
+        let mut fs = fs();
+        fs.insert("d/x", file("x"));
+        let options = Options {
+            delete: true,
+            trash: true,
+            move_files: true,
+            ..Options::default()
+        };
+        let plan = RenamePlan::new(
+            vec!["d".into(), "d/x".into()],
+            vec![vec![], vec!["y".into()]],
+            options,
+        );
+
+        // the memory filesystem has no trash, the directory is trashed once its child moved out
+        let Err(ApplyError::Failed {
+            step,
+            idx,
+            rollback,
+            ..
+        }) = plan.apply(&mut fs, None)
+        else {
+            panic!("trashing must fail");
+        };
+        assert_eq!(step, Step::Trash { path: "d".into() });
+        assert_eq!(idx, 1);
+        assert_eq!(rollback.len(), 1);
+        assert!(rollback.iter().all(|(_, _, res)| res.is_ok()));
+
+        assert_eq!(fs.get("d/x"), Some(&file("x")));
+        assert_eq!(fs.get("y"), None);
+    }
+}
@@ -0,0 +1,247 @@
+//! Reporting of problems, warnings and steps on stderr.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use evaki::copy;
+use evaki::escape;
+use evaki::git::Git;
+use evaki::plan::Step;
+use evaki::rename::ApplyError;
+use evaki::validate::{Location, Problem, Warning};
+
+/// Prints `(before, after)` pairs padded to the longest `before`.
+pub fn print_pairs(pairs: &[(&Path, &Path)]) {
+    let pad = pairs
+        .iter()
+        .map(|(before, _)| escape::display(before).chars().count())
+        .max()
+        .unwrap_or_default();
+
+    for (before, after) in pairs {
+        eprintln!(
+            "{:<pad$} -> {}",
+            escape::display(before),
+            escape::display(after)
+        );
+    }
+}
+
+/// Prints the problems grouped by their kind.
+pub fn problems(problems: &[Problem], before: &[PathBuf], after: &[Vec<PathBuf>]) {
+    let path = |(idx, nth): Location| after[idx][nth].as_path();
+
+    let removed: Vec<_> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::Removed { idx } => Some(before[*idx].as_path()),
+            _ => None,
+        })
+        .collect();
+
+    if !removed.is_empty() {
+        eprintln!("removed paths, pass --delete to delete them:");
+        for path in removed {
+            eprintln!("{}", escape::display(path));
+        }
+    }
+
+    let replaced_sources: BTreeSet<_> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::ReplacesSource { at } => Some(path(*at)),
+            _ => None,
+        })
+        .collect();
+
+    if !replaced_sources.is_empty() {
+        eprintln!("copies replacing other paths:");
+        for path in replaced_sources {
+            eprintln!("{}", escape::display(path));
+        }
+    }
+
+    let duplicates: Vec<_> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::Duplicate { at } => Some(at),
+            _ => None,
+        })
+        .collect();
+
+    if !duplicates.is_empty() {
+        eprintln!("duplicate renames:");
+        for at in duplicates {
+            eprintln!("-> {}", escape::display(path(at[0])));
+            for (idx, _) in at {
+                eprintln!("<- {}", escape::display(&before[*idx]));
+            }
+            eprintln!();
+        }
+    }
+
+    let renamed_ancestors: BTreeSet<_> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::RenamedAncestor { before, after, .. } => {
+                Some((before.as_path(), after.as_path()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    if !renamed_ancestors.is_empty() {
+        eprintln!("inline renamed ancestors:");
+        print_pairs(&renamed_ancestors.into_iter().collect::<Vec<_>>());
+    }
+
+    let changed_extensions: Vec<_> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::ChangedExtension { at } => Some((before[at.0].as_path(), path(*at))),
+            _ => None,
+        })
+        .collect();
+
+    if !changed_extensions.is_empty() {
+        eprintln!("changed extensions:");
+        print_pairs(&changed_extensions);
+    }
+
+    let conflicts: BTreeSet<_> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::Conflict { at } => Some(path(*at)),
+            _ => None,
+        })
+        .collect();
+
+    if !conflicts.is_empty() {
+        eprintln!("existing paths, pass --force or --backup to replace them:");
+        for path in conflicts {
+            eprintln!("{}", escape::display(path));
+        }
+    }
+
+    let backups: BTreeSet<_> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::ExistingBackup { backup, .. } => Some(backup),
+            _ => None,
+        })
+        .collect();
+
+    if !backups.is_empty() {
+        eprintln!("existing backups:");
+        for backup in backups {
+            eprintln!("{}", escape::display(backup));
+        }
+    }
+}
+
+/// Prints the warnings, cycles are shown by the steps breaking them up.
+pub fn warnings(warnings: &[Warning], before: &[PathBuf], after: &[Vec<PathBuf>]) {
+    let changed_extensions: Vec<_> = warnings
+        .iter()
+        .filter_map(|warning| match warning {
+            Warning::ChangedExtension { at: (idx, nth) } => {
+                Some((before[*idx].as_path(), after[*idx][*nth].as_path()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    if !changed_extensions.is_empty() {
+        eprintln!("warning: changed extensions, pass --stem-only to refuse this:");
+        print_pairs(&changed_extensions);
+    }
+}
+
+/// Returns the width the first path of steps is padded to.
+fn pad(steps: &[Step]) -> usize {
+    steps.iter().filter_map(Step::pad).max().unwrap_or_default()
+}
+
+/// Prints `steps`, noting those which are staged in `git` or copied across filesystems.
+pub fn steps(steps: &[Step], git: Option<&Git>) -> io::Result<()> {
+    let pad = pad(steps);
+    let tracked = match git {
+        Some(git) => git.tracked_steps(steps)?,
+        None => vec![false; steps.len()],
+    };
+
+    for (idx, step) in steps.iter().enumerate() {
+        let mut notes = vec![];
+        if tracked[idx] {
+            notes.push("git");
+        }
+        if let Step::Rename { from, to } = step {
+            if copy::crosses_devices(from, to) {
+                notes.push("copied across filesystems");
+            }
+        }
+
+        if notes.is_empty() {
+            eprintln!("{step:pad$}");
+        } else {
+            eprintln!("{step:pad$} ({})", notes.join(", "));
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the step of `steps` which failed to apply, the rollback of the steps applied before it
+/// and the remaining steps. Errors other than a failed step are returned.
+pub fn rolled_back(err: ApplyError, steps: &[Step]) -> Result<(), ApplyError> {
+    let ApplyError::Failed {
+        step,
+        idx,
+        err,
+        rollback,
+    } = err
+    else {
+        return Err(err);
+    };
+
+    let pad = pad(steps);
+    eprintln!("error: {step}: {err}");
+
+    // nothing needs to be rolled back if the first step failed
+    if !rollback.is_empty() {
+        eprintln!();
+        eprintln!("rolling back {} applied step(s):", rollback.len());
+
+        let mut failed = vec![];
+        for (step, inverse, res) in rollback {
+            match res {
+                Ok(()) => eprintln!("{inverse:pad$}"),
+                Err(err) => {
+                    eprintln!("{inverse:pad$} failed: {err}");
+                    failed.push(step);
+                }
+            }
+        }
+
+        eprintln!();
+        if failed.is_empty() {
+            eprintln!("all applied steps were rolled back");
+        } else {
+            eprintln!("the following steps could not be rolled back and remain applied:");
+            for step in failed.iter().rev() {
+                eprintln!("{step:pad$}");
+            }
+        }
+    }
+
+    let remaining = steps.get(idx + 1..).unwrap_or_default();
+    if !remaining.is_empty() {
+        eprintln!("the following steps were not applied:");
+        for step in remaining {
+            eprintln!("{step:pad$}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,340 @@
+//! Plans saved to a file, such that they can be reviewed and applied later.
+//!
+//! A saved plan consists of tab separated lines, a `cwd` line is followed by a line for each
+//! option which is set and a `path` line for each id. A `path` line holds the path before, its
+//! inode, size and modification time, followed by the paths after. A `target` line follows for
+//! each path after which isn't a path before, it holds the path and its inode, size and
+//! modification time, which are left out if it didn't exist. Fields are escaped like in the
+//! journal.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use evaki::copy::CopyMode;
+use evaki::rename::Options;
+use evaki::{escape, RenamePlan};
+
+/// The state of a path when its plan was saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl Fingerprint {
+    /// Returns the fingerprint of `path`, symlinks are not followed.
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let meta = fs::symlink_metadata(path)?;
+        Ok(Self {
+            inode: meta.ino(),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+        })
+    }
+}
+
+/// A plan loaded from a file.
+#[derive(Debug)]
+pub struct Saved {
+    /// The working directory relative paths of the plan are resolved against.
+    pub cwd: PathBuf,
+
+    /// Whether renames of tracked files are staged in the git index.
+    pub git: bool,
+
+    /// The plan.
+    pub plan: RenamePlan,
+
+    /// The fingerprint of each path before.
+    pub fingerprints: Vec<Fingerprint>,
+
+    /// The fingerprint of each path after which isn't a path before, `None` if it didn't exist.
+    pub targets: Vec<(PathBuf, Option<Fingerprint>)>,
+}
+
+impl Saved {
+    /// Returns the paths before and after which are created, missing or changed since the plan
+    /// was saved, each with the reason.
+    pub fn changed(&self) -> Vec<(&'static str, &Path)> {
+        let before = Iterator::zip(self.plan.before().iter(), self.fingerprints.iter())
+            .map(|(path, fingerprint)| (path, Some(fingerprint)));
+        let targets = self
+            .targets
+            .iter()
+            .map(|(path, fingerprint)| (path, fingerprint.as_ref()));
+
+        Iterator::chain(before, targets)
+            .filter_map(
+                |(path, fingerprint)| match (Fingerprint::of(path).ok(), fingerprint) {
+                    (current, saved) if current.as_ref() == saved => None,
+                    (Some(_), None) => Some(("created", path.as_path())),
+                    (Some(_), Some(_)) => Some(("changed", path.as_path())),
+                    (None, _) => Some(("missing", path.as_path())),
+                },
+            )
+            .collect()
+    }
+}
+
+/// Saves `plan` to `path`, along with the fingerprints of its paths before.
+pub fn save(path: &Path, plan: &RenamePlan, git: bool) -> io::Result<()> {
+    let line = |fields: &[&str]| fields.join("\t") + "\n";
+    let options = plan.options();
+
+    let mut content = line(&["cwd", &escape::escape(std::env::current_dir()?.as_os_str())]);
+    if git {
+        content.push_str(&line(&["git"]));
+    }
+    if let Some(mode) = options.copy_mode {
+        let mode = match mode {
+            CopyMode::Copy => "copy",
+            CopyMode::Hardlink => "hardlink",
+            CopyMode::Symlink => "symlink",
+            CopyMode::Reflink => "reflink",
+        };
+
+        content.push_str(&line(&["mode", mode]));
+    }
+    for (name, set) in [
+        ("delete", options.delete),
+        ("trash", options.trash),
+        ("move", options.move_files),
+        ("stem-only", options.stem_only),
+        ("force", options.force),
+        ("prune", options.prune),
+    ] {
+        if set {
+            content.push_str(&line(&[name]));
+        }
+    }
+    if let Some(suffix) = &options.backup_suffix {
+        content.push_str(&line(&["backup", &escape::escape(suffix.as_ref())]));
+    }
+
+    let fields = |kind: &str, path: &Path, fingerprint: Option<Fingerprint>| {
+        let mut fields = vec![kind.to_owned(), escape::escape(path.as_os_str())];
+        if let Some(fingerprint) = fingerprint {
+            fields.extend([
+                fingerprint.inode.to_string(),
+                fingerprint.size.to_string(),
+                fingerprint.mtime.to_string(),
+                fingerprint.mtime_nsec.to_string(),
+            ]);
+        }
+
+        fields
+    };
+
+    for (before, paths) in Iterator::zip(plan.before().iter(), plan.after()) {
+        let mut fields = fields("path", before, Some(Fingerprint::of(before)?));
+        fields.extend(paths.iter().map(|after| escape::escape(after.as_os_str())));
+
+        content.push_str(&(fields.join("\t") + "\n"));
+    }
+
+    // paths after may be replaced, so they must not change either
+    let sources: BTreeSet<&Path> = plan.before().iter().map(PathBuf::as_path).collect();
+    let targets: BTreeSet<&Path> = plan
+        .after()
+        .iter()
+        .flatten()
+        .map(PathBuf::as_path)
+        .filter(|after| !sources.contains(after))
+        .collect();
+
+    for target in targets {
+        let fingerprint = Fingerprint::of(target).ok();
+        content.push_str(&(fields("target", target, fingerprint).join("\t") + "\n"));
+    }
+
+    fs::write(path, content)
+}
+
+fn number<T: FromStr>(field: &OsStr) -> Option<T> {
+    field.to_str()?.parse().ok()
+}
+
+/// Parses the fields of a fingerprint.
+fn fingerprint(fields: &[&OsStr]) -> Option<Fingerprint> {
+    let [inode, size, mtime, mtime_nsec] = fields else {
+        return None;
+    };
+
+    Some(Fingerprint {
+        inode: number(inode)?,
+        size: number(size)?,
+        mtime: number(mtime)?,
+        mtime_nsec: number(mtime_nsec)?,
+    })
+}
+
+/// Loads the plan saved to `path`.
+pub fn load(path: &Path) -> io::Result<Saved> {
+    let content = fs::read_to_string(path)?;
+    let invalid = |line: usize| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid plan {} on line {line}", escape::display(path)),
+        )
+    };
+
+    let mut cwd = None;
+    let mut git = false;
+    let mut options = Options::default();
+    let mut before = vec![];
+    let mut after = vec![];
+    let mut fingerprints = vec![];
+    let mut targets = vec![];
+
+    for (idx, line) in content.lines().enumerate() {
+        let fields: Vec<_> = line
+            .split('\t')
+            .map(|field| escape::unescape(field.as_bytes()))
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid(idx + 1))?;
+
+        let fields: Vec<_> = fields.iter().map(|field| field.as_os_str()).collect();
+        match fields.as_slice() {
+            [kind, path] if *kind == "cwd" => cwd = Some(PathBuf::from(path)),
+            [kind] if *kind == "git" => git = true,
+            [kind, mode] if *kind == "mode" => {
+                options.copy_mode = Some(match mode.to_str() {
+                    Some("copy") => CopyMode::Copy,
+                    Some("hardlink") => CopyMode::Hardlink,
+                    Some("symlink") => CopyMode::Symlink,
+                    Some("reflink") => CopyMode::Reflink,
+                    _ => return Err(invalid(idx + 1)),
+                });
+            }
+            [kind] if *kind == "delete" => options.delete = true,
+            [kind] if *kind == "trash" => options.trash = true,
+            [kind] if *kind == "move" => options.move_files = true,
+            [kind] if *kind == "stem-only" => options.stem_only = true,
+            [kind] if *kind == "force" => options.force = true,
+            [kind] if *kind == "prune" => options.prune = true,
+            [kind, suffix] if *kind == "backup" => {
+                let suffix = suffix.to_str().ok_or_else(|| invalid(idx + 1))?;
+                options.backup_suffix = Some(suffix.to_owned());
+            }
+            [kind, path, fields @ ..] if *kind == "path" && fields.len() >= 4 => {
+                let (fields, paths) = fields.split_at(4);
+                let fingerprint = fingerprint(fields).ok_or_else(|| invalid(idx + 1))?;
+
+                before.push(PathBuf::from(path));
+                after.push(paths.iter().map(PathBuf::from).collect());
+                fingerprints.push(fingerprint);
+            }
+            [kind, path] if *kind == "target" => targets.push((PathBuf::from(path), None)),
+            [kind, path, fields @ ..] if *kind == "target" => {
+                let fingerprint = fingerprint(fields).ok_or_else(|| invalid(idx + 1))?;
+                targets.push((PathBuf::from(path), Some(fingerprint)));
+            }
+            _ => return Err(invalid(idx + 1)),
+        }
+    }
+
+    Ok(Saved {
+        cwd: cwd.ok_or_else(|| invalid(1))?,
+        git,
+        plan: RenamePlan::new(before, after, options),
+        fingerprints,
+        targets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for `test` with the files `names` in it.
+    fn dir(test: &str, names: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("evaki-saved-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in names {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        dir
+    }
+
+    fn plan(dir: &Path) -> RenamePlan {
+        let options = Options {
+            force: true,
+            backup_suffix: Some("~".into()),
+            ..Options::default()
+        };
+
+        RenamePlan::new(
+            vec![dir.join("a"), dir.join("b")],
+            vec![vec![dir.join("b")], vec![dir.join("c"), dir.join("x")]],
+            options,
+        )
+    }
+
+    #[test]
+    fn plans_round_trip() {
+        let dir = dir("round-trip", &["a", "b", "x"]);
+        let plan = plan(&dir);
+        save(&dir.join("plan"), &plan, true).unwrap();
+
+        let saved = load(&dir.join("plan")).unwrap();
+        assert!(saved.git);
+        assert_eq!(saved.cwd, std::env::current_dir().unwrap());
+        assert_eq!(saved.plan.before(), plan.before());
+        assert_eq!(saved.plan.after(), plan.after());
+        assert!(saved.plan.options().force);
+        assert_eq!(saved.plan.options().backup_suffix.as_deref(), Some("~"));
+        assert_eq!(
+            saved.fingerprints,
+            [
+                Fingerprint::of(&dir.join("a")).unwrap(),
+                Fingerprint::of(&dir.join("b")).unwrap(),
+            ]
+        );
+        assert_eq!(
+            saved.targets,
+            [
+                (dir.join("c"), None),
+                (
+                    dir.join("x"),
+                    Some(Fingerprint::of(&dir.join("x")).unwrap())
+                ),
+            ]
+        );
+        assert_eq!(saved.changed(), []);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn changed_targets_are_reported() {
+        let dir = dir("stale-target", &["a", "b", "x"]);
+        save(&dir.join("plan"), &plan(&dir), false).unwrap();
+
+        fs::write(dir.join("c"), "c").unwrap();
+        fs::remove_file(dir.join("x")).unwrap();
+        fs::write(dir.join("x"), "replaced").unwrap();
+
+        let saved = load(&dir.join("plan")).unwrap();
+        let (c, x) = (dir.join("c"), dir.join("x"));
+        assert_eq!(
+            saved.changed(),
+            [("created", c.as_path()), ("changed", x.as_path())]
+        );
+
+        fs::remove_file(dir.join("c")).unwrap();
+        let saved = load(&dir.join("plan")).unwrap();
+        assert_eq!(saved.changed(), [("changed", x.as_path())]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! Edit buffers which are kept until they were applied, such that they can be resumed.
+//!
+//! Each session is stored in `$XDG_STATE_HOME/evaki/buffers/<id>.buffer`, which is edited in
+//! place. The working directory and the original paths are stored next to it in `<id>.paths`,
+//! one escaped path per line, starting with the working directory. The working directory is
+//! followed by the flags the buffer was created with, separated by tabs.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use evaki::copy::CopyMode;
+use evaki::{escape, journal};
+
+/// The flags an edit buffer was created with, they decide how it's read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// How files are duplicated instead of renamed, if they are.
+    pub copy_mode: Option<CopyMode>,
+
+    /// Whether removed lines delete their file.
+    pub delete: bool,
+
+    /// Whether deleted files are not moved to the trash.
+    pub no_trash: bool,
+
+    /// Whether files may be moved to other directories.
+    pub move_files: bool,
+
+    /// Whether extensions are hidden in the buffer.
+    pub hide_extensions: bool,
+}
+
+impl Flags {
+    /// Returns the command line flags which are set.
+    pub fn args(&self) -> Vec<&'static str> {
+        let mode = self.copy_mode.map(|mode| match mode {
+            CopyMode::Copy => "--copy",
+            CopyMode::Hardlink => "--hardlink",
+            CopyMode::Symlink => "--symlink",
+            CopyMode::Reflink => "--reflink",
+        });
+
+        let flags = [
+            (self.delete, "--delete"),
+            (self.no_trash, "--no-trash"),
+            (self.move_files, "--move"),
+            (self.hide_extensions, "--hide-extensions"),
+        ];
+
+        Iterator::chain(
+            mode.into_iter(),
+            flags
+                .into_iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, arg)| arg),
+        )
+        .collect()
+    }
+
+    /// Sets the command line flag `arg`, returns `false` if it is unknown.
+    fn set(&mut self, arg: &str) -> bool {
+        match arg {
+            "--copy" => self.copy_mode = Some(CopyMode::Copy),
+            "--hardlink" => self.copy_mode = Some(CopyMode::Hardlink),
+            "--symlink" => self.copy_mode = Some(CopyMode::Symlink),
+            "--reflink" => self.copy_mode = Some(CopyMode::Reflink),
+            "--delete" => self.delete = true,
+            "--no-trash" => self.no_trash = true,
+            "--move" => self.move_files = true,
+            "--hide-extensions" => self.hide_extensions = true,
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+/// A kept edit buffer.
+#[derive(Debug)]
+pub struct Session {
+    /// The path of the edit buffer.
+    pub buffer: PathBuf,
+
+    /// The flags the edit buffer was created with.
+    pub flags: Flags,
+
+    /// The path of the original paths.
+    paths: PathBuf,
+}
+
+/// Returns the path of the original paths belonging to an edit buffer.
+fn paths_of(buffer: &Path) -> PathBuf {
+    buffer.with_extension("paths")
+}
+
+impl Session {
+    /// Creates a new session editing `before` with the initial `buffer`, which was created with
+    /// `flags`.
+    pub fn create(before: &[PathBuf], buffer: &[u8], flags: Flags) -> io::Result<Self> {
+        let dir = journal::dir()?.join("buffers");
+        fs::create_dir_all(&dir)?;
+
+        let mut content = escape::escape(std::env::current_dir()?.as_os_str());
+        for arg in flags.args() {
+            content.push('\t');
+            content.push_str(arg);
+        }
+        content.push('\n');
+
+        for path in before {
+            content.push_str(&escape::escape(path.as_os_str()));
+            content.push('\n');
+        }
+
+        let mut id = fs::read_dir(&dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                name.to_str()?.strip_suffix(".buffer")?.parse::<u64>().ok()
+            })
+            .max()
+            .map_or(1, |last| last + 1);
+
+        loop {
+            let path = dir.join(format!("{id}.buffer"));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(buffer)?;
+                    fs::write(paths_of(&path), content)?;
+                    return Ok(Self {
+                        paths: paths_of(&path),
+                        buffer: path,
+                        flags,
+                    });
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => id += 1,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Loads the session of the edit `buffer`, returns it with its working directory and the
+    /// original paths.
+    pub fn load(buffer: &Path) -> io::Result<(Self, PathBuf, Vec<PathBuf>)> {
+        let paths = paths_of(buffer);
+        let content = fs::read_to_string(&paths)?;
+
+        let invalid = |line: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid path on line {line} of {}", paths.display()),
+            )
+        };
+
+        let mut lines = content.lines();
+        let mut first = lines
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is empty", paths.display()),
+                )
+            })?
+            .split('\t');
+
+        let cwd = first
+            .next()
+            .and_then(|cwd| escape::unescape(cwd.as_bytes()))
+            .map(PathBuf::from)
+            .ok_or_else(|| invalid(1))?;
+
+        let mut flags = Flags::default();
+        for arg in first {
+            if !flags.set(arg) {
+                return Err(invalid(1));
+            }
+        }
+
+        let before = lines
+            .enumerate()
+            .map(|(idx, line)| {
+                escape::unescape(line.as_bytes())
+                    .map(PathBuf::from)
+                    .ok_or_else(|| invalid(idx + 2))
+            })
+            .collect::<io::Result<_>>()?;
+
+        let session = Self {
+            buffer: buffer.to_path_buf(),
+            paths,
+            flags,
+        };
+
+        Ok((session, cwd, before))
+    }
+
+    /// Removes the edit buffer and the original paths.
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_file(&self.buffer)?;
+        fs::remove_file(&self.paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_round_trip() {
+        let modes = [None, Some(CopyMode::Copy), Some(CopyMode::Symlink)];
+        for (idx, copy_mode) in modes.into_iter().enumerate() {
+            let flags = Flags {
+                copy_mode,
+                delete: idx != 1,
+                no_trash: idx == 2,
+                move_files: idx == 0,
+                hide_extensions: idx != 0,
+            };
+
+            let mut parsed = Flags::default();
+            for arg in flags.args() {
+                assert!(parsed.set(arg), "{arg}");
+            }
+            assert_eq!(parsed, flags);
+        }
+
+        assert!(!Flags::default().set("--force"));
+    }
+
+    #[test]
+    fn sessions_are_loaded() {
+        let dir = std::env::temp_dir().join(format!("evaki-session-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let buffer = dir.join("1.buffer");
+        fs::write(&buffer, "").unwrap();
+
+        fs::write(
+            paths_of(&buffer),
+            "/work\\tdir\t--hardlink\t--move\na\\nb\nc\n",
+        )
+        .unwrap();
+        let (session, cwd, before) = Session::load(&buffer).unwrap();
+        assert_eq!(cwd, Path::new("/work\tdir"));
+        assert_eq!(before, [PathBuf::from("a\nb"), PathBuf::from("c")]);
+        assert_eq!(
+            session.flags,
+            Flags {
+                copy_mode: Some(CopyMode::Hardlink),
+                move_files: true,
+                ..Flags::default()
+            }
+        );
+
+        fs::write(paths_of(&buffer), "/work\t--unknown\n").unwrap();
+        assert!(Session::load(&buffer).is_err());
+
+        session.remove().unwrap();
+        assert!(!buffer.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
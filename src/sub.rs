@@ -0,0 +1,234 @@
+//! Non-interactive sed-style substitutions of paths.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use evaki::plan;
+use regex::bytes::{Regex, RegexBuilder};
+
+/// The part of a path substitutions are applied to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Scope {
+    /// The file name without its extension.
+    Stem,
+
+    /// The file name.
+    #[default]
+    Name,
+
+    /// The whole path.
+    Path,
+}
+
+/// A substitution of the form `s/REGEX/REPLACEMENT/FLAGS`.
+///
+/// Any character can be used as the delimiter, it can be escaped with a backslash. In the
+/// replacement `&` inserts the whole match and `\1` to `\9` insert capture groups. The flags `g`
+/// and `i` replace all matches instead of the first and match case insensitively.
+///
+/// Regexes match bytes, such that paths which are not valid UTF-8 are matched as a whole. `.`
+/// matches any byte and `\xNN` a byte, classes like `\w` and the `i` flag only apply to ASCII,
+/// other characters are matched literally.
+#[derive(Debug, Clone)]
+pub struct Substitution {
+    regex: Regex,
+    replacement: Vec<u8>,
+    global: bool,
+}
+
+/// Splits `text` at unescaped occurrences of `delim`, escapes are kept.
+fn split(text: &str, delim: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars();
+    while let Some(char) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        match char {
+            '\\' => {
+                part.push(char);
+                part.extend(chars.next());
+            }
+            _ if char == delim => parts.push(String::new()),
+            _ => part.push(char),
+        }
+    }
+
+    parts
+}
+
+/// Translates a sed replacement into the syntax of [`Regex::replace`].
+fn replacement(text: &str, delim: char) -> Result<Vec<u8>, String> {
+    let mut replacement = String::new();
+    let mut chars = text.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => replacement.push_str(&format!("${{{digit}}}")),
+                Some('n') => replacement.push('\n'),
+                Some('t') => replacement.push('\t'),
+                Some('$') => replacement.push_str("$$"),
+                Some(char @ ('\\' | '&')) => replacement.push(char),
+                Some(char) if char == delim => replacement.push(char),
+                Some(char) => return Err(format!("unknown escape `\\{char}` in replacement")),
+                None => return Err("trailing backslash in replacement".into()),
+            },
+            '&' => replacement.push_str("${0}"),
+            '$' => replacement.push_str("$$"),
+            _ => replacement.push(char),
+        }
+    }
+
+    Ok(replacement.into_bytes())
+}
+
+impl Substitution {
+    /// Parses a substitution of the form `s/REGEX/REPLACEMENT/FLAGS`.
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let mut chars = expr.chars();
+        let (Some('s'), Some(delim)) = (chars.next(), chars.next()) else {
+            return Err("expected s/REGEX/REPLACEMENT/FLAGS".into());
+        };
+
+        if delim.is_alphanumeric() || delim.is_whitespace() || delim == '\\' {
+            return Err(format!("invalid delimiter `{delim}`"));
+        }
+
+        let [regex, replacement_text, flags] =
+            <[String; 3]>::try_from(split(chars.as_str(), delim))
+                .map_err(|_| "expected s/REGEX/REPLACEMENT/FLAGS".to_owned())?;
+
+        // escaped delimiters are taken literally
+        let regex = regex.replace(&format!("\\{delim}"), &regex::escape(&delim.to_string()));
+
+        let mut builder = RegexBuilder::new(&regex);
+        builder.unicode(false);
+        let mut global = false;
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'i' => _ = builder.case_insensitive(true),
+                _ => return Err(format!("unknown flag `{flag}`")),
+            }
+        }
+
+        Ok(Self {
+            regex: builder.build().map_err(|err| err.to_string())?,
+            replacement: replacement(&replacement_text, delim)?,
+            global,
+        })
+    }
+
+    /// Applies this substitution to `text`.
+    fn apply(&self, text: &[u8]) -> Vec<u8> {
+        let limit = if self.global { 0 } else { 1 };
+        self.regex
+            .replacen(text, limit, self.replacement.as_slice())
+            .into_owned()
+    }
+}
+
+/// Applies the substitutions one after another to the `scope` of `path`.
+///
+/// Returns `None` if the file name or, if only the stem is substituted, the stem would be left
+/// empty.
+pub fn substitute(path: &Path, subs: &[Substitution], scope: Scope) -> Option<PathBuf> {
+    let bytes = path.as_os_str().as_bytes();
+
+    // the range of the file name, excluding trailing slashes
+    let name_end = bytes.len() - bytes.iter().rev().take_while(|&&byte| byte == b'/').count();
+    let name_start = bytes[..name_end]
+        .iter()
+        .rposition(|&byte| byte == b'/')
+        .map_or(0, |idx| idx + 1);
+
+    let name = &bytes[name_start..name_end];
+    if name.is_empty() || name == b"." || name == b".." {
+        return Some(path.to_path_buf());
+    }
+
+    let (start, end) = match scope {
+        Scope::Path => (0, bytes.len()),
+        Scope::Name => (name_start, name_end),
+        Scope::Stem => {
+            let name = Path::new(OsStr::from_bytes(&bytes[..name_end]));
+            (name_start, plan::split_extension(name).0.len())
+        }
+    };
+
+    let mut part = bytes[start..end].to_vec();
+    for sub in subs {
+        part = sub.apply(&part);
+    }
+
+    if scope != Scope::Path && part.is_empty() {
+        return None;
+    }
+
+    let mut substituted = bytes[..start].to_vec();
+    substituted.extend(part);
+    substituted.extend(&bytes[end..]);
+
+    let substituted = PathBuf::from(OsString::from_vec(substituted));
+    substituted.file_name().is_some().then_some(substituted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sub(expr: &str) -> Substitution {
+        Substitution::parse(expr).unwrap()
+    }
+
+    #[test]
+    fn scopes() {
+        let path = Path::new("dir/photo.jpg");
+        let subs = [sub("s/o/0/g")];
+
+        assert_eq!(
+            substitute(path, &subs, Scope::Stem),
+            Some("dir/ph0t0.jpg".into())
+        );
+        assert_eq!(
+            substitute(path, &subs, Scope::Name),
+            Some("dir/ph0t0.jpg".into())
+        );
+        assert_eq!(
+            substitute(path, &[sub("s/i/I/")], Scope::Path),
+            Some("dIr/photo.jpg".into())
+        );
+    }
+
+    #[test]
+    fn empty_names_are_refused() {
+        let path = Path::new("dir/photo.jpg");
+
+        assert_eq!(substitute(path, &[sub("s/.*//")], Scope::Stem), None);
+        assert_eq!(substitute(path, &[sub("s/.*//")], Scope::Name), None);
+        assert_eq!(
+            substitute(path, &[sub("s/photo//")], Scope::Name),
+            Some("dir/.jpg".into())
+        );
+    }
+    #[test]
+    fn bytes_are_matched() {
+        let path = Path::new(OsStr::from_bytes(b"dir/lat\xE9.txt"));
+
+        assert_eq!(
+            substitute(path, &[sub("s/.*/same/")], Scope::Stem),
+            Some("dir/same.txt".into())
+        );
+        assert_eq!(
+            substitute(path, &[sub("s/.*/same.TXT/")], Scope::Name),
+            Some("dir/same.TXT".into())
+        );
+        assert_eq!(
+            substitute(path, &[sub("s/\\xE9/e/")], Scope::Name),
+            Some("dir/late.txt".into())
+        );
+        assert_eq!(
+            substitute(Path::new("café"), &[sub("s/é/e/")], Scope::Name),
+            Some("cafe".into())
+        );
+    }
+}
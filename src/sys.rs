@@ -0,0 +1,313 @@
+//! Thin wrappers around platform specific syscalls.
+
+use std::ffi::CString;
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Returns the current local time formatted as `YYYY-MM-DDThh:mm:ss`.
+pub fn local_time() -> String {
+    // SAFETY: a null pointer is allowed and a zeroed tm is a valid output
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+    )
+}
+
+/// Atomically swaps `a` and `b`, both paths must exist.
+#[cfg(target_os = "linux")]
+pub fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a = c_path(a)?;
+    let b = c_path(b)?;
+
+    // SAFETY: both paths are valid nul terminated strings
+    let res = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Renames `from` to `to`, failing if `to` already exists.
+///
+/// Falls back to checking for `to` before renaming if the filesystem doesn't support this
+/// atomically.
+pub fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    match renameat2_noreplace(from, to) {
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::Unsupported | io::ErrorKind::InvalidInput
+            ) =>
+        {
+            if std::fs::symlink_metadata(to).is_ok() {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+
+            std::fs::rename(from, to)
+        }
+        res => res,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    let from = c_path(from)?;
+    let to = c_path(to)?;
+
+    // SAFETY: both paths are valid nul terminated strings
+    let res = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn renameat2_noreplace(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Makes `target` share the contents of `source` using a reflink.
+#[cfg(target_os = "linux")]
+pub fn reflink(source: &File, target: &File) -> io::Result<()> {
+    // SAFETY: both file descriptors are valid for the duration of the call
+    let res = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_source: &File, _target: &File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sets the access and modification times of `path` to those in `meta`, symlinks are not
+/// followed.
+pub fn copy_times(meta: &Metadata, path: &Path) -> io::Result<()> {
+    let path = c_path(path)?;
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime() as _,
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: meta.mtime() as _,
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    ];
+
+    // SAFETY: the path is a valid nul terminated string and times has two elements
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Returns the names of the extended attributes of `path`, symlinks are not followed.
+#[cfg(target_os = "linux")]
+fn xattr_names(path: &CString) -> io::Result<Vec<CString>> {
+    loop {
+        // SAFETY: the path is a valid nul terminated string, a null buffer queries the size
+        let len = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut names = vec![0u8; len as usize];
+
+        // SAFETY: the buffer is valid for `len` bytes
+        let len =
+            unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                // attributes were added in the meantime
+                continue;
+            }
+
+            return Err(err);
+        }
+
+        names.truncate(len as usize);
+        return Ok(names
+            .split(|&byte| byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| CString::new(name).unwrap())
+            .collect());
+    }
+}
+
+/// Copies the extended attributes of `from` to `to`, symlinks are not followed.
+///
+/// Attributes which can't be set on `to`, because they are not supported by its filesystem or
+/// require privileges, are skipped.
+#[cfg(target_os = "linux")]
+pub fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    let from = c_path(from)?;
+    let to = c_path(to)?;
+
+    let names = match xattr_names(&from) {
+        Ok(names) => names,
+        Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    for name in names {
+        // SAFETY: the path and name are valid nul terminated strings, a null buffer queries the
+        // size
+        let len = unsafe { libc::lgetxattr(from.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut value = vec![0u8; len as usize];
+
+        // SAFETY: the buffer is valid for `len` bytes
+        let len = unsafe {
+            libc::lgetxattr(
+                from.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: the buffer is valid for `len` bytes
+        let res = unsafe {
+            libc::lsetxattr(
+                to.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                len as usize,
+                0,
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if !matches!(err.raw_os_error(), Some(libc::ENOTSUP | libc::EPERM)) {
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn copy_xattrs(_from: &Path, _to: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("evaki-sys-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn existing_targets_are_not_replaced() {
+        let dir = temp_dir("noreplace");
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+        fs::create_dir(dir.join("dir")).unwrap();
+
+        for target in ["b", "dir"] {
+            let err = rename_noreplace(&dir.join("a"), &dir.join(target)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists, "{target}");
+        }
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "b");
+
+        rename_noreplace(&dir.join("a"), &dir.join("c")).unwrap();
+        assert!(!dir.join("a").exists());
+        assert_eq!(fs::read_to_string(dir.join("c")).unwrap(), "a");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn paths_are_exchanged() {
+        let dir = temp_dir("exchange");
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::create_dir(dir.join("b")).unwrap();
+
+        exchange(&dir.join("a"), &dir.join("b")).unwrap();
+        assert!(dir.join("a").is_dir());
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "a");
+
+        let err = exchange(&dir.join("a"), &dir.join("missing")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! Moving files to the trash as described by the freedesktop.org trash specification.
+
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::sys;
+
+/// Returns the home trash directory.
+fn home_trash() -> io::Result<PathBuf> {
+    if let Some(data) = std::env::var_os("XDG_DATA_HOME").filter(|data| !data.is_empty()) {
+        return Ok(PathBuf::from(data).join("Trash"));
+    }
+
+    match std::env::var_os("HOME").filter(|home| !home.is_empty()) {
+        Some(home) => Ok(PathBuf::from(home).join(".local/share/Trash")),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "neither XDG_DATA_HOME nor HOME are set",
+        )),
+    }
+}
+
+/// Returns the top most ancestor of `path` which is on the same device.
+fn top_dir(path: &Path, dev: u64) -> io::Result<PathBuf> {
+    let mut top = path;
+    for ancestor in path.ancestors().skip(1) {
+        if fs::metadata(ancestor)?.dev() != dev {
+            break;
+        }
+
+        top = ancestor;
+    }
+
+    Ok(top.to_path_buf())
+}
+
+/// Percent encodes a path for use in a trash info file.
+fn encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    encoded
+}
+
+/// A path which was moved to the trash.
+#[derive(Debug)]
+pub struct Trashed {
+    /// The path of the trashed file.
+    pub file: PathBuf,
+
+    /// The path of the trash info file.
+    pub info: PathBuf,
+}
+
+/// Moves `path` to the trash of the filesystem it is on.
+///
+/// Paths on the same device as the home trash go to the home trash, any other path goes to the
+/// `.Trash-$uid` directory at the top of its mount.
+pub fn trash(path: &Path) -> io::Result<Trashed> {
+    let path = std::path::absolute(path)?;
+    let dev = fs::symlink_metadata(&path)?.dev();
+
+    let home = home_trash()?;
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&home)?;
+
+    let (trash, original) = if fs::metadata(&home)?.dev() == dev {
+        (home, path.clone())
+    } else {
+        // SAFETY: getuid is always successful
+        let uid = unsafe { libc::getuid() };
+        let top = top_dir(&path, dev)?;
+        let original = path.strip_prefix(&top).unwrap().to_path_buf();
+
+        (top.join(format!(".Trash-{uid}")), original)
+    };
+
+    let files = trash.join("files");
+    let info = trash.join("info");
+    for dir in [&files, &info] {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+    // reserve a unique name by creating its info file
+    for n in 1.. {
+        let mut unique = name.to_os_string();
+        if n > 1 {
+            unique.push(format!(".{n}"));
+        }
+
+        let mut info_path = info.join(&unique).into_os_string();
+        info_path.push(".trashinfo");
+        let info_path = PathBuf::from(info_path);
+
+        let mut info_file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_path)
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        };
+
+        let target = files.join(&unique);
+        if fs::symlink_metadata(&target).is_ok() {
+            drop(info_file);
+            fs::remove_file(&info_path)?;
+            continue;
+        }
+
+        let res = write!(
+            info_file,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            encode(&original),
+            sys::local_time(),
+        )
+        .and_then(|_| info_file.sync_all())
+        .and_then(|_| fs::rename(&path, &target));
+
+        if let Err(err) = res {
+            fs::remove_file(&info_path)?;
+            return Err(err);
+        }
+
+        return Ok(Trashed {
+            file: target,
+            info: info_path,
+        });
+    }
+
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    #[test]
+    fn paths_are_percent_encoded() {
+        let path = Path::new(OsStr::from_bytes(b"/a b/caf\xC3\xA9/100%/\xFF~x_y-z.txt"));
+        assert_eq!(encode(path), "/a%20b/caf%C3%A9/100%25/%FF~x_y-z.txt");
+    }
+
+    #[test]
+    fn top_dirs_stay_on_the_device() {
+        let dir = std::env::temp_dir();
+        let dev = fs::metadata(&dir).unwrap().dev();
+
+        let top = top_dir(&dir, dev).unwrap();
+        assert!(dir.starts_with(&top));
+        assert_eq!(fs::metadata(&top).unwrap().dev(), dev);
+        if let Some(parent) = top.parent() {
+            assert_ne!(fs::metadata(parent).unwrap().dev(), dev);
+        }
+
+        // a path is its own top if its parent is on another device
+        assert_eq!(top_dir(&dir, u64::MAX).unwrap(), dir);
+    }
+
+    #[test]
+    fn files_are_trashed_with_their_info() {
+        let dir = std::env::temp_dir().join(format!("evaki-trash-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        std::env::set_var("XDG_DATA_HOME", dir.join("data"));
+
+        let mut trashed = vec![];
+        for contents in ["first", "second"] {
+            fs::write(dir.join("a b"), contents).unwrap();
+            trashed.push(trash(&dir.join("a b")).unwrap());
+        }
+
+        let files = dir.join("data/Trash/files");
+        assert_eq!(trashed[0].file, files.join("a b"));
+        assert_eq!(trashed[1].file, files.join("a b.2"));
+        assert_eq!(fs::read_to_string(&trashed[1].file).unwrap(), "second");
+        assert!(fs::symlink_metadata(dir.join("a b")).is_err());
+
+        let info = fs::read_to_string(&trashed[0].info).unwrap();
+        assert_eq!(trashed[0].info, dir.join("data/Trash/info/a b.trashinfo"));
+        assert!(info.starts_with(&format!(
+            "[Trash Info]\nPath={}\nDeletionDate=",
+            encode(&dir.join("a b"))
+        )));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+//! Validation of edited paths before any changes are planned.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use crate::vfs::Filesystem;
+use crate::{escape, plan};
+
+/// The edits which are allowed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rules {
+    /// Whether paths are copied instead of renamed, removed lines are skipped then.
+    pub copy: bool,
+
+    /// Whether removing all paths of an id is allowed.
+    pub delete: bool,
+
+    /// Whether ancestors of paths may be changed.
+    pub move_files: bool,
+
+    /// Whether changing the extension of a file is refused.
+    pub stem_only: bool,
+}
+
+/// The position of an edited path, the index of its id and its index among the paths of the id.
+pub type Location = (usize, usize);
+
+/// A problem with the edited paths, which prevents them from being applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// All paths of the id at this index were removed, but removing paths is not allowed.
+    Removed { idx: usize },
+
+    /// A copy would replace another original path.
+    ReplacesSource { at: Location },
+
+    /// Multiple paths are the same.
+    Duplicate { at: Vec<Location> },
+
+    /// The ancestor of a path was changed, but moving paths is not allowed.
+    RenamedAncestor {
+        at: Location,
+        before: PathBuf,
+        after: PathBuf,
+    },
+
+    /// The extension of a file was changed, but only stems may be changed.
+    ChangedExtension { at: Location },
+
+    /// A path outside of the batch would be replaced, but replacing paths is not allowed.
+    Conflict { at: Location },
+
+    /// A path outside of the batch would be moved aside to a backup which already exists.
+    ExistingBackup { at: Location, backup: PathBuf },
+}
+
+/// A noteworthy edit which doesn't prevent the edited paths from being applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The extension of a file was changed.
+    ChangedExtension { at: Location },
+
+    /// The ids whose renames form a cycle, each renamed to the original path of the next, they are
+    /// applied using exchanges or temporary names.
+    Cycle { ids: Vec<usize> },
+}
+
+/// The problems and warnings of edited paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// The problems preventing the paths from being applied.
+    pub problems: Vec<Problem>,
+
+    /// The warnings which don't prevent the paths from being applied.
+    pub warnings: Vec<Warning>,
+}
+
+impl Diagnostics {
+    /// Returns whether there are no problems.
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Problem {
+    /// Returns messages describing this problem, each with the location it refers to, if any.
+    pub fn annotations(&self, before: &[PathBuf]) -> Vec<(Option<Location>, String)> {
+        match self {
+            Problem::Removed { idx } => vec![(
+                None,
+                format!(
+                    "the line of id {} was removed, restore it or pass --delete: {}",
+                    idx + 1,
+                    escape::display(&before[*idx]),
+                ),
+            )],
+            Problem::ReplacesSource { at } => {
+                vec![(Some(*at), "copies can't replace other listed paths".into())]
+            }
+            Problem::Duplicate { at } => at
+                .iter()
+                .map(|&(idx, nth)| {
+                    let others: Vec<_> = at
+                        .iter()
+                        .filter(|&&other| other != (idx, nth))
+                        .map(|(idx, _)| (idx + 1).to_string())
+                        .collect();
+
+                    let message = format!("duplicate path, also used by id {}", others.join(", "));
+                    (Some((idx, nth)), message)
+                })
+                .collect(),
+            Problem::RenamedAncestor { at, before, after } => vec![(
+                Some(*at),
+                format!(
+                    "ancestor changed from {} to {}, pass --move to move files",
+                    escape::display(before),
+                    escape::display(after),
+                ),
+            )],
+            Problem::ChangedExtension { at } => {
+                vec![(Some(*at), "only the stem of files may be changed".into())]
+            }
+            Problem::Conflict { at } => vec![(
+                Some(*at),
+                "path exists, pass --force or --backup to replace it".into(),
+            )],
+            Problem::ExistingBackup { at, backup } => vec![(
+                Some(*at),
+                format!("backup {} exists", escape::display(backup)),
+            )],
+        }
+    }
+}
+
+impl Warning {
+    /// Returns messages describing this warning, each with the location it refers to.
+    pub fn annotations(&self) -> Vec<(Location, String)> {
+        match self {
+            Warning::ChangedExtension { at } => vec![(
+                *at,
+                "extension changed, pass --stem-only to refuse this".into(),
+            )],
+            Warning::Cycle { ids } => ids
+                .iter()
+                .map(|&idx| {
+                    let others: Vec<_> = ids
+                        .iter()
+                        .filter(|&&other| other != idx)
+                        .map(|other| (other + 1).to_string())
+                        .collect();
+
+                    let message = format!("renamed in a cycle with id {}", others.join(", "));
+                    ((idx, 0), message)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Returns the parent of `path`, relative paths without one are inside of `.`.
+fn get_ancestor(path: &Path) -> &Path {
+    match path.parent() {
+        Some(ancestor) if ancestor.as_os_str().is_empty() => Path::new("."),
+        Some(ancestor) => ancestor,
+        None => path,
+    }
+}
+
+/// Returns the locations of edited files whose extension was changed, directories of `fs` are
+/// skipped.
+pub fn changed_extensions(
+    before: &[PathBuf],
+    after: &[Vec<PathBuf>],
+    fs: &impl Filesystem,
+) -> Vec<Location> {
+    let mut changed = vec![];
+    for (idx, (before, paths)) in Iterator::zip(before.iter(), after.iter()).enumerate() {
+        for (nth, after) in paths.iter().enumerate() {
+            if after != before
+                && !fs.is_dir(before)
+                && plan::split_extension(before).1 != plan::split_extension(after).1
+            {
+                changed.push((idx, nth));
+            }
+        }
+    }
+
+    changed
+}
+
+/// Validates the `after` paths of each id against the `before` paths in `fs`.
+pub fn validate(
+    rules: Rules,
+    before: &[PathBuf],
+    after: &[Vec<PathBuf>],
+    fs: &impl Filesystem,
+) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    let sources: BTreeSet<&Path> = before.iter().map(PathBuf::as_path).collect();
+    let mut reverse_map: BTreeMap<&Path, Vec<Location>> = BTreeMap::new();
+
+    for (idx, (before, paths)) in Iterator::zip(before.iter(), after.iter()).enumerate() {
+        if paths.is_empty() && !rules.delete && !rules.copy {
+            problems.push(Problem::Removed { idx });
+        }
+
+        for (nth, after) in paths.iter().enumerate() {
+            // sources of copies are kept, so they can't be replaced
+            if rules.copy && after != before && sources.contains(after.as_path()) {
+                problems.push(Problem::ReplacesSource { at: (idx, nth) });
+            }
+
+            reverse_map.entry(after).or_default().push((idx, nth));
+
+            if rules.move_files {
+                continue;
+            }
+
+            let (before_ancestor, after_ancestor) = (get_ancestor(before), get_ancestor(after));
+            if before_ancestor != after_ancestor {
+                problems.push(Problem::RenamedAncestor {
+                    at: (idx, nth),
+                    before: before_ancestor.to_path_buf(),
+                    after: after_ancestor.to_path_buf(),
+                });
+            }
+        }
+    }
+
+    problems.extend(
+        reverse_map
+            .into_values()
+            .filter(|at| at.len() > 1)
+            .map(|at| Problem::Duplicate { at }),
+    );
+
+    if rules.stem_only {
+        problems.extend(
+            changed_extensions(before, after, fs)
+                .into_iter()
+                .map(|at| Problem::ChangedExtension { at }),
+        );
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{Memory, Node};
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    fn fs() -> Memory {
+        let mut fs = Memory::new();
+        for path in ["a.txt", "b.txt", "dir/c.txt"] {
+            fs.insert(path, Node::File(vec![]));
+        }
+
+        fs
+    }
+
+    #[test]
+    fn valid_renames() {
+        let before = paths(&["a.txt", "b.txt"]);
+        let after = vec![paths(&["b.txt"]), paths(&["a.txt"])];
+
+        assert_eq!(validate(Rules::default(), &before, &after, &fs()), []);
+    }
+
+    #[test]
+    fn removed_paths() {
+        let before = paths(&["a.txt", "b.txt"]);
+        let after = vec![paths(&["a.txt"]), vec![]];
+
+        assert_eq!(
+            validate(Rules::default(), &before, &after, &fs()),
+            [Problem::Removed { idx: 1 }]
+        );
+
+        let rules = Rules {
+            delete: true,
+            ..Rules::default()
+        };
+        assert_eq!(validate(rules, &before, &after, &fs()), []);
+    }
+
+    #[test]
+    fn duplicate_paths() {
+        let before = paths(&["a.txt", "b.txt"]);
+        let after = vec![paths(&["c.txt"]), paths(&["c.txt"])];
+
+        assert_eq!(
+            validate(Rules::default(), &before, &after, &fs()),
+            [Problem::Duplicate {
+                at: vec![(0, 0), (1, 0)]
+            }]
+        );
+    }
+
+    #[test]
+    fn renamed_ancestors() {
+        let before = paths(&["dir/c.txt", "a.txt", "b.txt"]);
+        let after = vec![
+            paths(&["other/c.txt", "c.txt"]),
+            paths(&["sub/a.txt"]),
+            paths(&["/b.txt"]),
+        ];
+
+        let renamed = |at, before: &str, after: &str| Problem::RenamedAncestor {
+            at,
+            before: before.into(),
+            after: after.into(),
+        };
+        assert_eq!(
+            validate(Rules::default(), &before, &after, &fs()),
+            [
+                renamed((0, 0), "dir", "other"),
+                renamed((0, 1), "dir", "."),
+                renamed((1, 0), ".", "sub"),
+                renamed((2, 0), ".", "/"),
+            ]
+        );
+
+        let rules = Rules {
+            move_files: true,
+            ..Rules::default()
+        };
+        assert_eq!(validate(rules, &before, &after, &fs()), []);
+    }
+
+    #[test]
+    fn changed_extensions_with_stem_only() {
+        let before = paths(&["a.txt", "dir"]);
+        let after = vec![paths(&["a.md"]), paths(&["dir.d"])];
+        let rules = Rules {
+            stem_only: true,
+            ..Rules::default()
+        };
+
+        assert_eq!(
+            validate(rules, &before, &after, &fs()),
+            [Problem::ChangedExtension { at: (0, 0) }]
+        );
+    }
+
+    #[test]
+    fn copies_replacing_sources() {
+        let before = paths(&["a.txt", "b.txt"]);
+        let after = vec![paths(&["a.txt", "b.txt"]), vec![]];
+        let rules = Rules {
+            copy: true,
+            ..Rules::default()
+        };
+
+        assert_eq!(
+            validate(rules, &before, &after, &fs()),
+            [Problem::ReplacesSource { at: (0, 1) }]
+        );
+    }
+}
@@ -0,0 +1,394 @@
+//! Filesystems which plans are applied to, either the real one or one kept in memory.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::copy::{self, CopyMode};
+use crate::trash::{self, Trashed};
+use crate::{plan, sys};
+
+/// The operations steps are applied through.
+///
+/// Paths are not followed if they are symlinks and existing paths are never replaced, unless
+/// stated otherwise.
+pub trait Filesystem {
+    /// Returns whether `path` exists.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Returns whether `path` is a directory.
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Returns the names of the entries of the directory `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    /// Renames `from` to `to`.
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Swaps `a` and `b` with each other, both paths must exist.
+    fn exchange(&mut self, a: &Path, b: &Path) -> io::Result<()>;
+
+    /// Copies or links `from` to `to`, directories are copied recursively.
+    fn copy(&mut self, from: &Path, to: &Path, mode: CopyMode) -> io::Result<()>;
+
+    /// Removes the file or directory at `path` recursively.
+    fn remove(&mut self, path: &Path) -> io::Result<()>;
+
+    /// Creates the directory `path`, its ancestor must exist.
+    fn create_dir(&mut self, path: &Path) -> io::Result<()>;
+
+    /// Removes the empty directory `path`.
+    fn remove_dir(&mut self, path: &Path) -> io::Result<()>;
+
+    /// Moves `path` to the trash.
+    fn trash(&mut self, path: &Path) -> io::Result<Trashed>;
+
+    /// Restores the `trashed` path to `path`.
+    fn restore(&mut self, trashed: &Trashed, path: &Path) -> io::Result<()>;
+}
+
+impl<F: Filesystem + ?Sized> Filesystem for &mut F {
+    fn exists(&self, path: &Path) -> bool {
+        (**self).exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        (**self).is_dir(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        (**self).read_dir(path)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).rename(from, to)
+    }
+
+    fn exchange(&mut self, a: &Path, b: &Path) -> io::Result<()> {
+        (**self).exchange(a, b)
+    }
+
+    fn copy(&mut self, from: &Path, to: &Path, mode: CopyMode) -> io::Result<()> {
+        (**self).copy(from, to, mode)
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        (**self).remove(path)
+    }
+
+    fn create_dir(&mut self, path: &Path) -> io::Result<()> {
+        (**self).create_dir(path)
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> io::Result<()> {
+        (**self).remove_dir(path)
+    }
+
+    fn trash(&mut self, path: &Path) -> io::Result<Trashed> {
+        (**self).trash(path)
+    }
+
+    fn restore(&mut self, trashed: &Trashed, path: &Path) -> io::Result<()> {
+        (**self).restore(trashed, path)
+    }
+}
+
+/// The filesystem of the operating system.
+///
+/// Renames across filesystems fall back to copying and exchanges which can't be done atomically
+/// go through a temporary name.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Os;
+
+impl Filesystem for Os {
+    fn exists(&self, path: &Path) -> bool {
+        std::fs::symlink_metadata(path).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        std::fs::symlink_metadata(path).is_ok_and(|meta| meta.is_dir())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect()
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        match sys::rename_noreplace(from, to) {
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => copy::move_across(from, to),
+            res => res,
+        }
+    }
+
+    fn exchange(&mut self, a: &Path, b: &Path) -> io::Result<()> {
+        match sys::exchange(a, b) {
+            Ok(()) => Ok(()),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::Unsupported
+                        | io::ErrorKind::InvalidInput
+                        | io::ErrorKind::CrossesDevices
+                ) =>
+            {
+                // the paths can't be exchanged atomically, go through a temporary name
+                let temp = plan::temp_name(a, &BTreeSet::from([a.into(), b.into()]), &Os);
+                self.rename(a, &temp)?;
+
+                // completed renames are undone, such that a failed exchange changes nothing
+                if let Err(err) = self.rename(b, a) {
+                    let _ = self.rename(&temp, a);
+                    return Err(err);
+                }
+
+                if let Err(err) = self.rename(&temp, b) {
+                    let _ = self.rename(a, b);
+                    let _ = self.rename(&temp, a);
+                    return Err(err);
+                }
+
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn copy(&mut self, from: &Path, to: &Path, mode: CopyMode) -> io::Result<()> {
+        copy::copy(from, to, mode)
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        if std::fs::symlink_metadata(path)?.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    }
+
+    fn create_dir(&mut self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir(path)
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir(path)
+    }
+
+    fn trash(&mut self, path: &Path) -> io::Result<Trashed> {
+        trash::trash(path)
+    }
+
+    fn restore(&mut self, trashed: &Trashed, path: &Path) -> io::Result<()> {
+        sys::rename_noreplace(&trashed.file, path)?;
+        std::fs::remove_file(&trashed.info)
+    }
+}
+
+/// An entry of a [`Memory`] filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// A file with its contents.
+    File(Vec<u8>),
+
+    /// A directory, its entries are stored separately.
+    Dir,
+
+    /// A symbolic link to its target.
+    Symlink(PathBuf),
+}
+
+/// A filesystem kept in memory, such that plans can be tried without touching any files.
+///
+/// Paths are compared by their components, relative paths and absolute paths are separate. The
+/// empty path and `/` always exist as directories. Trashing is not supported.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    nodes: BTreeMap<PathBuf, Node>,
+}
+
+/// Returns an error of `kind` for `path`.
+fn error(kind: io::ErrorKind, path: &Path) -> io::Error {
+    io::Error::new(kind, format!("{kind}: {}", path.display()))
+}
+
+impl Memory {
+    /// Creates an empty filesystem.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts `node` at `path`, missing ancestors are created and an existing node is replaced.
+    pub fn insert(&mut self, path: impl AsRef<Path>, node: Node) {
+        let path = path.as_ref();
+        for ancestor in path.ancestors().skip(1) {
+            if ancestor.parent().is_some() {
+                self.nodes.insert(ancestor.to_path_buf(), Node::Dir);
+            }
+        }
+
+        self.nodes.insert(path.to_path_buf(), node);
+    }
+
+    /// Returns the node at `path`.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&Node> {
+        self.nodes.get(path.as_ref())
+    }
+
+    /// Returns all paths and their nodes, ancestors come before their children.
+    pub fn nodes(&self) -> impl Iterator<Item = (&Path, &Node)> {
+        self.nodes.iter().map(|(path, node)| (path.as_path(), node))
+    }
+
+    /// Returns the paths of `path` and all of its descendants.
+    fn subtree(&self, path: &Path) -> Vec<PathBuf> {
+        self.nodes
+            .range(path.to_path_buf()..)
+            .map(|(path, _)| path)
+            .take_while(|descendant| descendant.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    /// Fails unless `path` is free and its ancestor is a directory.
+    fn check_free(&self, path: &Path) -> io::Result<()> {
+        if self.exists(path) {
+            return Err(error(io::ErrorKind::AlreadyExists, path));
+        }
+
+        match path.parent() {
+            Some(ancestor) if !self.is_dir(ancestor) => Err(error(io::ErrorKind::NotFound, path)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes `path` and its descendants, returns them relative to `path`.
+    fn take(&mut self, path: &Path) -> io::Result<Vec<(PathBuf, Node)>> {
+        if !self.nodes.contains_key(path) {
+            return Err(error(io::ErrorKind::NotFound, path));
+        }
+
+        Ok(self
+            .subtree(path)
+            .into_iter()
+            .map(|descendant| {
+                let node = self.nodes.remove(&descendant).unwrap();
+                (descendant.strip_prefix(path).unwrap().to_path_buf(), node)
+            })
+            .collect())
+    }
+
+    /// Inserts nodes relative to `path`.
+    fn put(&mut self, path: &Path, nodes: Vec<(PathBuf, Node)>) {
+        for (relative, node) in nodes {
+            match relative.as_os_str().is_empty() {
+                true => self.nodes.insert(path.to_path_buf(), node),
+                false => self.nodes.insert(path.join(relative), node),
+            };
+        }
+    }
+}
+
+impl Filesystem for Memory {
+    fn exists(&self, path: &Path) -> bool {
+        path.parent().is_none() || self.nodes.contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.parent().is_none() || self.nodes.get(path) == Some(&Node::Dir)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        if !self.is_dir(path) {
+            return Err(error(io::ErrorKind::NotADirectory, path));
+        }
+
+        Ok(self
+            .nodes
+            .keys()
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name())
+            .map(|name| name.to_os_string())
+            .collect())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check_free(to)?;
+        if to.starts_with(from) {
+            return Err(error(io::ErrorKind::InvalidInput, to));
+        }
+
+        let nodes = self.take(from)?;
+        self.put(to, nodes);
+        Ok(())
+    }
+
+    fn exchange(&mut self, a: &Path, b: &Path) -> io::Result<()> {
+        if a.starts_with(b) || b.starts_with(a) {
+            return Err(error(io::ErrorKind::InvalidInput, a));
+        }
+
+        if !self.nodes.contains_key(b) {
+            return Err(error(io::ErrorKind::NotFound, b));
+        }
+
+        let a_nodes = self.take(a)?;
+        let b_nodes = self.take(b)?;
+        self.put(a, b_nodes);
+        self.put(b, a_nodes);
+        Ok(())
+    }
+
+    fn copy(&mut self, from: &Path, to: &Path, mode: CopyMode) -> io::Result<()> {
+        self.check_free(to)?;
+        if !self.nodes.contains_key(from) {
+            return Err(error(io::ErrorKind::NotFound, from));
+        }
+
+        if mode == CopyMode::Symlink {
+            self.nodes
+                .insert(to.to_path_buf(), Node::Symlink(from.to_path_buf()));
+            return Ok(());
+        }
+
+        let nodes = self
+            .subtree(from)
+            .into_iter()
+            .map(|descendant| {
+                let node = self.nodes[&descendant].clone();
+                (descendant.strip_prefix(from).unwrap().to_path_buf(), node)
+            })
+            .collect();
+
+        self.put(to, nodes);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        self.take(path).map(|_| ())
+    }
+
+    fn create_dir(&mut self, path: &Path) -> io::Result<()> {
+        self.check_free(path)?;
+        self.nodes.insert(path.to_path_buf(), Node::Dir);
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> io::Result<()> {
+        if !self.read_dir(path)?.is_empty() {
+            return Err(error(io::ErrorKind::DirectoryNotEmpty, path));
+        }
+
+        self.nodes.remove(path);
+        Ok(())
+    }
+
+    fn trash(&mut self, path: &Path) -> io::Result<Trashed> {
+        Err(error(io::ErrorKind::Unsupported, path))
+    }
+
+    fn restore(&mut self, _trashed: &Trashed, path: &Path) -> io::Result<()> {
+        Err(error(io::ErrorKind::Unsupported, path))
+    }
+}
@@ -0,0 +1,210 @@
+//! Recursive expansion of directories into their contents.
+
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use regex::bytes::Regex;
+
+/// The type of entries to keep when expanding directories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EntryType {
+    /// Regular files.
+    #[value(name = "f", alias = "file")]
+    File,
+
+    /// Directories.
+    #[value(name = "d", alias = "dir")]
+    Dir,
+}
+
+/// Options for expanding directories.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Only keep entries of this type.
+    pub entry_type: Option<EntryType>,
+
+    /// Only keep entries matching any of these globs, unless empty.
+    pub include: Vec<Glob>,
+
+    /// Skip entries matching any of these globs, directories are not descended into.
+    pub exclude: Vec<Glob>,
+
+    /// Only keep entries matching any of these regexes, unless empty.
+    pub include_regex: Vec<Regex>,
+
+    /// Skip entries matching any of these regexes, directories are not descended into.
+    pub exclude_regex: Vec<Regex>,
+
+    /// Whether hidden entries are kept and descended into.
+    pub hidden: bool,
+
+    /// The maximum depth to descend to, direct contents have a depth of 1.
+    pub max_depth: Option<usize>,
+
+    /// Whether entries ignored by `.gitignore` and `.ignore` files are skipped.
+    pub ignore_files: bool,
+}
+
+/// Globs and regexes matched against paths relative to the expanded directory.
+///
+/// Globs without a `/` are matched against the name of an entry only.
+#[derive(Debug)]
+struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+    regexes: Vec<Regex>,
+}
+
+impl Patterns {
+    fn new(globs: &[Glob], regexes: &[Regex]) -> Result<Self, globset::Error> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for glob in globs {
+            match glob.glob().contains('/') {
+                true => paths.add(glob.clone()),
+                false => names.add(glob.clone()),
+            };
+        }
+
+        Ok(Self {
+            names: names.build()?,
+            paths: paths.build()?,
+            regexes: regexes.to_vec(),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty() && self.regexes.is_empty()
+    }
+
+    fn matches(&self, relative: &Path) -> bool {
+        relative
+            .file_name()
+            .is_some_and(|name| self.names.is_match(name))
+            || self.paths.is_match(relative)
+            || self
+                .regexes
+                .iter()
+                .any(|regex| regex.is_match(relative.as_os_str().as_bytes()))
+    }
+}
+
+/// Returns `path` relative to the directory `root` which is expanded.
+fn relative(root: &Path, path: &Path) -> PathBuf {
+    path.strip_prefix(root).unwrap_or(path).to_path_buf()
+}
+
+/// Replaces directories in `paths` with their contents, other paths are kept as is.
+pub fn expand(paths: &[PathBuf], options: &Options) -> io::Result<Vec<PathBuf>> {
+    let include =
+        Patterns::new(&options.include, &options.include_regex).map_err(io::Error::other)?;
+
+    let mut expanded = vec![];
+    for root in paths {
+        if !root.is_dir() {
+            expanded.push(root.clone());
+            continue;
+        }
+
+        // excluded directories are not descended into
+        let exclude =
+            Patterns::new(&options.exclude, &options.exclude_regex).map_err(io::Error::other)?;
+        let walk = WalkBuilder::new(root)
+            .standard_filters(options.ignore_files)
+            .require_git(false)
+            .hidden(!options.hidden)
+            .max_depth(options.max_depth)
+            .filter_entry({
+                let root = root.clone();
+                move |entry| entry.depth() == 0 || !exclude.matches(&relative(&root, entry.path()))
+            })
+            .build();
+
+        for entry in walk {
+            let entry = entry.map_err(io::Error::other)?;
+            if entry.depth() == 0 {
+                continue;
+            }
+
+            let is_type = match options.entry_type {
+                Some(EntryType::File) => entry.file_type().is_some_and(|ty| ty.is_file()),
+                Some(EntryType::Dir) => entry.file_type().is_some_and(|ty| ty.is_dir()),
+                None => true,
+            };
+
+            if is_type && (include.is_empty() || include.matches(&relative(root, entry.path()))) {
+                expanded.push(entry.into_path());
+            }
+        }
+    }
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn directories_are_expanded() {
+        let root = std::env::temp_dir().join(format!("evaki-walk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for path in [
+            "a.txt",
+            "b.rs",
+            ".hidden",
+            "ignored",
+            "sub/c.txt",
+            "sub/d.rs",
+        ] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(root.join(".gitignore"), "ignored\n").unwrap();
+
+        let expand = |options: &Options| {
+            let mut expanded: Vec<_> = expand(&[root.clone(), root.join("a.txt")], options)
+                .unwrap()
+                .into_iter()
+                .map(|path| relative(&root, &path))
+                .collect();
+            expanded.sort();
+            expanded
+        };
+
+        let options = Options {
+            ignore_files: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            expand(&options),
+            ["a.txt", "a.txt", "b.rs", "sub", "sub/c.txt", "sub/d.rs"].map(PathBuf::from)
+        );
+
+        let options = Options {
+            entry_type: Some(EntryType::File),
+            exclude: vec![Glob::new("sub").unwrap()],
+            hidden: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            expand(&options),
+            [".gitignore", ".hidden", "a.txt", "a.txt", "b.rs", "ignored"].map(PathBuf::from)
+        );
+
+        let options = Options {
+            include: vec![Glob::new("*.rs").unwrap()],
+            max_depth: Some(1),
+            ..Options::default()
+        };
+        assert_eq!(expand(&options), ["a.txt", "b.rs"].map(PathBuf::from));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}